@@ -0,0 +1,52 @@
+//! Local Netatmo API for development, point NetatmoConfig::api_url and auth_url to the printed addresses.
+//! Usage: netatmo-mock [ADDRESS] [FAILURE [METHOD]], i.e. `netatmo-mock 127.0.0.1:8080 usage-reached getmeasure`
+#[path = "../worker/netatmo/mock.rs"]
+#[allow(dead_code)] // requests inspection is for tests
+mod mock;
+
+use std::net::SocketAddr;
+use mock::{MockNetatmo, Failure, ALL_METHODS};
+
+const DEFAULT_ADDRESS : &str = "127.0.0.1:8080";
+
+#[tokio::main]
+async fn main() {
+  env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
+
+  let args : Vec<String> = std::env::args().skip(1).collect();
+  let addr = args.first().map_or(DEFAULT_ADDRESS, |a| a.as_str());
+  let addr : SocketAddr = match addr.parse() {
+    Ok( a ) => a,
+    Err( e ) => {
+      log::error!("Wrong address {}: {}. Exiting.", addr, e);
+      return;
+    },
+  };
+
+  let failure = match args.get(1) {
+    None => None,
+    Some( name ) => match Failure::parse(name) {
+      Some( f ) => Some( f ),
+      None => {
+        log::error!("Unknown failure {}, expected one of {:?}. Exiting.", name, Failure::NAMES);
+        return;
+      },
+    },
+  };
+
+  let server = match MockNetatmo::start(addr) {
+    Ok( s ) => s,
+    Err( e ) => {
+      log::error!("{}. Exiting.", e);
+      return;
+    },
+  };
+  let method = args.get(2).map_or(ALL_METHODS, |m| m.as_str());
+  server.fail(method, failure);
+
+  log::info!("Netatmo API is at {}, authorization is at {}", server.api_url(), server.auth_url());
+  if let Some( failure ) = failure {
+      log::info!("{} answers with {:?}", method, failure);
+  }
+  std::future::pending::<()>().await;
+}
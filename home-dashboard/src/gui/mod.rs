@@ -6,10 +6,12 @@ use std::thread;
 use log;
 use egui_extras::{TableBuilder, Column};
 use std::path::Path;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use crate::interface::*;
 use crate::worker::worker_thread;
-use crate::worker::ddc_display::DisplayState;
+use crate::worker::ddc_display::{DisplayState, DisplayFeature, FeatureState, Preset};
 
 mod images;
 use images::Images;
@@ -19,10 +21,46 @@ use texts::{Texts, Language};
 
 #[derive(Default)]
 pub struct GUIState {
-  aeropex_switch_state : bool,
-  edifier_switch_state : bool,
+  bt_switch_states : HashMap<DeviceKey, bool>,
+  show_discovery : bool,
+  // measure shown on the chart of a tile, temperature if absent
+  chart_measures : HashMap<String, Measure>,
+  // week instead of day on all charts
+  history_week : bool,
+  // index of shown NetatmoConfig::locations
+  location : usize,
+  // continuous feature value requested by user and time of request, shown until display reports it
+  pending_values : HashMap<(DisplayKey, DisplayFeature), (u16, Instant)>,
 }
 
+// user clicked on a row name or on the chart of a tile
+enum ChartAction {
+  Select(Measure),
+  TogglePeriod,
+}
+
+// user pressed a control of display tile
+enum DisplayAction {
+  SetFeature(DisplayFeature, u16),
+  // PresetConfig::name
+  SetPreset(String),
+}
+
+// rows of values_group_table, data is shorter than names when there is no reading
+struct ValueRows<'a> {
+  names : &'a [&'a str],
+  data : &'a [String],
+  units : &'a [&'a str],
+}
+
+const OUTDOOR_TILE : &str = "Outdoor Group Table";
+// °C, change of room set-point by one press
+const SETPOINT_STEP : f32 = 0.5;
+// change of continuous display feature by one press
+const FEATURE_STEP : u16 = 5;
+// display value is shown again if it doesn't reach requested one in time, i.e. command failed
+const PENDING_VALUE_TIMEOUT : Duration = Duration::from_secs(3);
+
 pub struct HomeDashboard {
   state : HomeState,
   gui_state : GUIState,
@@ -30,6 +68,10 @@ pub struct HomeDashboard {
   sender : Sender<HomeCommand>,
   images : Images,
   texts : Texts,
+  // configured rooms, in order of tiles
+  locations : Vec<LocationConfig>,
+  // seconds, older readings are greyed out
+  stale_after : i64,
 }
 
 impl HomeDashboard {
@@ -54,6 +96,10 @@ impl HomeDashboard {
     style.visuals.selection.bg_fill = Color32::DARK_GREEN;
     ctx.set_style(style);
 
+    let icon_names : Vec<String> = cfg.bt_config.devices.iter().filter_map(|d| d.icon.clone()).collect();
+    let locations = cfg.netatmo_config.locations.clone();
+    let stale_after = i64::from(cfg.netatmo_config.stale_after_minutes) * 60;
+
     // it detaches but we are control it via channels
     thread::spawn(move|| worker_thread(worker_sender, worker_receiver, ctx, cfg));
 
@@ -62,8 +108,10 @@ impl HomeDashboard {
      gui_state : GUIState::default(),
      receiver : gui_receiver,
      sender : gui_sender,
-     images : Images::new(Path::new("home-dashboard/resources")),
+     images : Images::new(Path::new("home-dashboard/resources"), &icon_names),
      texts : Texts::new(Language::Russian),
+     locations,
+     stale_after,
    }
   }
 
@@ -76,26 +124,47 @@ impl HomeDashboard {
   fn bt_switch(&self,
     ui: &mut Ui,
     width : f32,
-    label : &str,
-    connect_state : bool,
-    switch_state : bool,
-    connect_command : HomeCommand,
-    disconnect_command : HomeCommand) -> bool {
+    device : &BluetoothDeviceState,
+    switch_state : bool) -> bool {
 
         let mut switch_state = switch_state;
 
         ui.allocate_ui(Vec2::new(width, 400.0), |ui| {
             ui.vertical_centered(|ui| {
                 let text_color = Color32::from_rgb(242, 174, 73);
-                ui.label( RichText::new(label).color(text_color).heading() );
+                if let Some( icon ) = device.icon.as_ref().and_then(|name| self.images.icons.get(name)) {
+                    icon.show_scaled(ui, 0.4);
+                }
+                ui.label( RichText::new(&device.name).color(text_color).heading() );
+                if let Some( group ) = &device.group {
+                    ui.label( RichText::new(group).color(text_color).small() );
+                }
                 ui.add_visible(false, Separator::default());
-                indicator(ui, connect_state);
+                indicator(ui, device.is_connected);
+                if device.is_audio_output {
+                    ui.label( RichText::new(self.texts.audio_output()).color(Color32::GREEN) );
+                }
+                if let Some( battery ) = device.battery {
+                    const LOW_BATTERY_LEVEL : u8 = 20;
+                    let battery_color = if battery <= LOW_BATTERY_LEVEL { Color32::RED } else { Color32::GREEN };
+                    ui.label( RichText::new(format!("{}%", battery)).color(battery_color).heading() );
+                }
+                if let Some( reconnect ) = &device.reconnect {
+                    let label = ui.label( RichText::new(format!("{} ({})", self.texts.bt_reconnecting(), reconnect.attempt)).color(text_color) );
+                    if let Some( err ) = &reconnect.last_error {
+                        label.on_hover_text(err);
+                    }
+                }
                 ui.add_visible(false, Separator::default());
-                if switch_button(ui, &mut switch_state, label).clicked() {
+                if !device.is_available {
+                    ui.label( RichText::new(self.texts.bt_unavailable()).color(Color32::GRAY) );
+                    return;
+                }
+                if switch_button(ui, &mut switch_state, &device.name).clicked() {
                     if switch_state {
-                        self.send_command( connect_command );
+                        self.send_command( HomeCommand::Connect( device.key.clone() ) );
                     } else {
-                        self.send_command( disconnect_command );
+                        self.send_command( HomeCommand::Disconnect( device.key.clone() ) );
                     }
                 }
             })
@@ -111,30 +180,80 @@ impl HomeDashboard {
         ui.group(|ui| {
             ui.label( RichText::new("Аудио").heading().color(title_color).size(20.0) );
         });
+        let adapter = self.state.bt_state.adapter.clone();
+        ui.horizontal(|ui| {
+            indicator(ui, adapter.present && adapter.powered && !adapter.blocked);
+            let adapter_color = if adapter.blocked { Color32::RED } else { Color32::from_rgb(242, 174, 73) };
+            ui.label( RichText::new(self.texts.bt_adapter_state(&adapter)).color(adapter_color) );
+            if adapter.present || adapter.blocked {
+                let label = if adapter.powered { self.texts.turn_off() } else { self.texts.turn_on() };
+                if ui.button(label).clicked() {
+                    self.send_command( HomeCommand::SetAdapterPowered( !adapter.powered ) );
+                }
+            }
+        });
+        if !self.gui_state.show_discovery && adapter.powered && ui.button(self.texts.bt_discover()).clicked() {
+            self.gui_state.show_discovery = true;
+            self.send_command( HomeCommand::StartDiscovery );
+        }
         ui.horizontal_centered(|ui| {
             let w = ui.available_width();
-            ui.add_visible(false, Separator::default().spacing(w/4.0) );
-            let new_switch_state = self.bt_switch(ui, w/4.0, "AEROPEX",
-              self.state.bt_state.is_aeropex_connected,
-              self.gui_state.aeropex_switch_state,
-              HomeCommand::ConnectAeropex,
-              HomeCommand::DisconnectAeropex,
-            );
-            self.gui_state.aeropex_switch_state = new_switch_state;
-
-            let new_switch_state = self.bt_switch(ui, w/4.0, "EDIFIER",
-              self.state.bt_state.is_edifier_connected,
-              self.gui_state.edifier_switch_state,
-              HomeCommand::ConnectEdifier,
-              HomeCommand::DisconnectEdifier,
-            );
-            self.gui_state.edifier_switch_state = new_switch_state;
+            let tile_w = w / (self.state.bt_state.devices.len().max(2) as f32 + 2.0);
+            ui.add_visible(false, Separator::default().spacing(tile_w) );
+            for device in &self.state.bt_state.devices {
+                let switch_state = self.gui_state.bt_switch_states.get(&device.key).copied().unwrap_or(device.is_connected);
+                let new_switch_state = self.bt_switch(ui, tile_w, device, switch_state);
+                self.gui_state.bt_switch_states.insert(device.key.clone(), new_switch_state);
+
+                // exclusive group works like radio buttons, worker disconnects the others
+                if new_switch_state && !switch_state && device.is_exclusive {
+                    for peer in self.state.bt_state.devices.iter().filter(|d| d.key != device.key && d.group == device.group) {
+                        self.gui_state.bt_switch_states.insert(peer.key.clone(), false);
+                    }
+                }
+            }
        });
     });
   }
 
-  fn outdoor_group_table(&self, ui: &mut Ui, wd : &Option<WeatherData> ) {
+  fn discovery_window(&mut self, ctx : &egui::Context)
+  {
+    let mut open = true;
+    egui::Window::new(self.texts.bt_discover())
+      .open(&mut open)
+      .collapsible(false)
+      .show(ctx, |ui| {
+        if self.state.bt_state.adapter.discovering {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label( self.texts.bt_searching() );
+            });
+        }
+        for device in &self.state.bt_state.discovered {
+            ui.horizontal(|ui| {
+                let name = device.name.as_ref().unwrap_or(&device.mac);
+                ui.label( RichText::new(name).heading() );
+                if let Some( rssi ) = device.rssi {
+                    ui.label( format!("{} dBm", rssi) );
+                }
+                if ui.button( self.texts.bt_pair() ).clicked() {
+                    self.send_command( HomeCommand::Pair( device.mac.clone() ) );
+                }
+            });
+        }
+      });
+
+    if !open {
+        self.gui_state.show_discovery = false;
+        self.send_command( HomeCommand::StopDiscovery );
+    }
+  }
+
+  fn outdoor_group_table(&self, ui: &mut Ui, location : Option<&LocationState> ) -> Option<ChartAction> {
+    let wd = location.and_then(|l| l.weather_data.as_ref());
     let name_texts = vec![self.texts.temperature(), self.texts.humidity(), self.texts.pressure()];
+    let measures = [Measure::Temperature, Measure::Humidity, Measure::Pressure];
+    let mut action = None;
     let unit_texts = vec!["°C", "%", "mmHg"];
     let text_sizes = vec![40.0, 40.0, 40.0];
     let text_color = Color32::from_rgb(242, 174, 73);
@@ -143,6 +262,8 @@ impl HomeDashboard {
 
     let mut data_texts = vec![String::new(); 3];
     let mut data_trends : Vec<Option<Trend>> = vec![None; 3];
+    let mut data_stale = [false; 3];
+    let outdoor_status = wd.and_then(|wd| wd.outdoor_weather.as_ref()).map(|od| &od.status);
 
     if let Some( wd ) = wd {
 
@@ -150,18 +271,32 @@ impl HomeDashboard {
             data_texts[0] = format!("{:.1}", od.temperature);
             data_trends[0] = od.temperature_trend.clone();
             data_texts[1] = format!("{}", od.humidity);
+            data_stale[0] = self.is_stale(&od.status);
+            data_stale[1] = data_stale[0];
         }
 
+        // pressure is measured by main module
         let pressure = wd.pressure / 1.333223684; //to mmHg
         data_texts[2] = format!("{:.1}", pressure);
         data_trends[2] =  wd.pressure_trend.clone();
+        data_stale[2] = self.is_stale(&wd.status);
     }
 
-    ui.push_id("Outdoor Group Table", |ui| {
+    ui.push_id(OUTDOOR_TILE, |ui| {
         ui.vertical_centered(|ui| {
             ui.group(|ui| {
                     ui.label( RichText::new("Во дворе").heading().color(title_color).size(20.0) );
             });
+            if let Some( err ) = &self.state.netatmo_error {
+                // click retries right away instead of waiting for the backoff
+                let error = Label::new( RichText::new(format!("{} ({})", self.texts.netatmo_error(&err.kind), err.attempt)).color(Color32::RED) ).sense(Sense::click());
+                if ui.add(error).on_hover_text(&err.message).clicked() {
+                    self.send_command( HomeCommand::RefreshNetatmo );
+                }
+            }
+            if let Some( status ) = outdoor_status {
+                self.status_caption(ui, status);
+            }
             let w = ui.available_width();
             TableBuilder::new(ui)
                 .column( Column::exact(w/2.) )
@@ -173,13 +308,17 @@ impl HomeDashboard {
                         let text_size = text_sizes[row_index];
                         row.col(|ui| {
                             ui.with_layout(Layout::left_to_right(Align::Center), |ui| {
-                                ui.label( RichText::new(name_texts[row_index]).heading().color(text_color).size(text_size) );
+                                let name = Label::new( RichText::new(name_texts[row_index]).heading().color(text_color).size(text_size) ).sense(Sense::click());
+                                if ui.add(name).clicked() {
+                                    action = Some( ChartAction::Select( measures[row_index] ) );
+                                }
                             });
                         });
                     if let Some( txt ) = data_texts.get(row_index) {
                         row.col(|ui| {
                             ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
-                                ui.label( RichText::new(txt).heading().color(data_color).size(text_size) );
+                                let color = if data_stale[row_index] { Color32::GRAY } else { data_color };
+                                ui.label( RichText::new(txt).heading().color(color).size(text_size) );
                              });
                         });
                         row.col(|ui| {
@@ -195,12 +334,21 @@ impl HomeDashboard {
                     };
                 });
             });
+            let measure = self.chart_measure(OUTDOOR_TILE);
+            if self.history_plot(ui, OUTDOOR_TILE, location.and_then(|l| l.outdoor_history.series.get(&measure))) {
+                action = Some( ChartAction::TogglePeriod );
+            }
         });
     });
+
+    action
   }
 
-  fn home_group_table(&self, ui: &mut Ui, title : &str, wd : &Option<AirQualityData> ) {
+  fn home_group_table(&self, ui: &mut Ui, title : &str, location : Option<&LocationState> ) -> Option<ChartAction> {
+    let wd = location.and_then(|l| l.room_data.get(title));
     let name_texts = vec![self.texts.temperature(), self.texts.humidity(), self.texts.co2(), self.texts.noise()];
+    // there is no history of noise
+    let measures = [Some( Measure::Temperature ), Some( Measure::Humidity ), Some( Measure::CO2 ), None];
     let unit_texts = vec!["°C", "%", "ppm", "dB"];
 
     let mut data_texts = Vec::<String>::new();
@@ -208,11 +356,57 @@ impl HomeDashboard {
              data_texts.push( format!("{:.1}", wd.room_temperature) );
              data_texts.push( format!("{}", wd.room_humidity) );
              data_texts.push( format!("{}", wd.room_co2) );
-             data_texts.push( format!("{}", wd.room_noise) );
+             data_texts.push( wd.room_noise.map(|n| format!("{}", n)).unwrap_or_default() );
+    }
+
+    let mut plot_clicked = false;
+    let series = location.and_then(|l| l.room_history.get(title)).and_then(|h| h.series.get( &self.chart_measure(title) ));
+    let status = wd.map(|wd| &wd.status);
+    let rows = ValueRows { names : &name_texts, data : &data_texts, units : &unit_texts };
+    let clicked_row = self.values_group_table(ui, title, status, rows, |ui| {
+        plot_clicked = self.history_plot(ui, title, series);
+    });
+
+    if plot_clicked {
+        return Some( ChartAction::TogglePeriod );
     }
+    clicked_row.and_then(|i| measures[i]).map(ChartAction::Select)
+  }
+
+  fn rain_group_table(&self, ui: &mut Ui, rd : &RainData ) {
+    let name_texts = vec![self.texts.rain_last_hour(), self.texts.rain_last_day()];
+    let unit_texts = vec!["mm", "mm"];
+    let data_texts = vec![format!("{:.1}", rd.last_hour), format!("{:.1}", rd.last_day)];
+
+    let rows = ValueRows { names : &name_texts, data : &data_texts, units : &unit_texts };
+    self.values_group_table(ui, self.texts.rain(), Some( &rd.status ), rows, |_| ());
+  }
+
+  fn wind_group_table(&self, ui: &mut Ui, wd : &WindData ) {
+    let name_texts = vec![self.texts.wind_strength(), self.texts.wind_gust(), self.texts.wind_direction()];
+    let unit_texts = vec!["km/h", "km/h", ""];
+    let data_texts = vec![
+        format!("{}", wd.strength),
+        format!("{}", wd.gust_strength),
+        format!("{} {}°", self.texts.compass_point(wd.angle), wd.angle),
+    ];
+
+    let rows = ValueRows { names : &name_texts, data : &data_texts, units : &unit_texts };
+    self.values_group_table(ui, self.texts.wind(), Some( &wd.status ), rows, |_| ());
+  }
 
+  /// titled table of "name value unit" rows, values are left blank if there is no data
+  /// and greyed out if status is stale.
+  /// add_contents is placed under the table, returns index of clicked name
+  fn values_group_table(&self,
+    ui: &mut Ui,
+    title : &str,
+    status : Option<&ReadingStatus>,
+    rows : ValueRows,
+    add_contents : impl FnOnce(&mut Ui)) -> Option<usize> {
+    let mut clicked_row = None;
     let text_color = Color32::from_rgb(242, 174, 73);
-    let data_color = Color32::GREEN;
+    let data_color = if status.is_some_and(|s| self.is_stale(s)) { Color32::GRAY } else { Color32::GREEN };
     let title_color = Color32::from_rgb(105, 209, 203);
 
     ui.push_id(title, |ui| {
@@ -220,19 +414,25 @@ impl HomeDashboard {
             ui.group(|ui| {
                     ui.label( RichText::new(title).heading().color(title_color).size(20.0) );
             });
+            if let Some( status ) = status {
+                self.status_caption(ui, status);
+            }
             let w = ui.available_width();
             TableBuilder::new(ui)
                 .column( Column::exact(w/2.) )
                 .column( Column::exact(w/4.) )
                 .column( Column::exact(w/4.) )
                 .body(|body| {
-                    body.rows(60.0,  name_texts.len(), |row_index, mut row| {
+                    body.rows(60.0,  rows.names.len(), |row_index, mut row| {
                         row.col(|ui| {
                             ui.with_layout(Layout::left_to_right(Align::Center), |ui| {
-                                ui.label( RichText::new(name_texts[row_index]).heading().color(text_color).size(40.0) );
+                                let name = Label::new( RichText::new(rows.names[row_index]).heading().color(text_color).size(40.0) ).sense(Sense::click());
+                                if ui.add(name).clicked() {
+                                    clicked_row = Some( row_index );
+                                }
                             });
                         });
-                        if let Some( txt ) = data_texts.get(row_index) {
+                        if let Some( txt ) = rows.data.get(row_index) {
                             row.col(|ui| {
                                 ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
                                     ui.label( RichText::new(txt).heading().color(data_color).size(40.0) );
@@ -240,57 +440,302 @@ impl HomeDashboard {
                             });
                             row.col(|ui| {
                                 ui.with_layout(Layout::left_to_right(Align::Center), |ui| {
-                                    ui.label( RichText::new(unit_texts[row_index]).heading().color(text_color).size(40.0) );
+                                    ui.label( RichText::new(rows.units[row_index]).heading().color(text_color).size(40.0) );
                                 });
                             });
                        };
                    });
              });
+            add_contents(ui);
         });
     });
+
+    clicked_row
   }
 
-  fn display_group_table(&self, ui: &mut Ui, dd : &Option<DisplayState> ) {
-    let name_texts = vec![self.texts.brightness(), self.texts.preset()];
+  fn is_stale(&self, status : &ReadingStatus) -> bool {
+    if !status.reachable {
+        return true;
+    }
+    match status.measured_at {
+      None => true,
+      Some( t ) => chrono::Utc::now().timestamp() - t > self.stale_after,
+    }
+  }
 
-    let mut data_texts = Vec::<String>::new();
-    if let Some( dd ) = dd {
-             data_texts.push( if let Some( br ) = dd.brightness { format!("{}", br) } else { String::new() } );
-             data_texts.push( if let Some( pr ) = &dd.preset { format!("{}", self.texts.show_preset(pr)) } else { String::new() } );
+  /// age of stale readings, nothing for fresh ones
+  fn status_caption(&self, ui : &mut Ui, status : &ReadingStatus) {
+    if !self.is_stale(status) {
+        return;
     }
+    let minutes = status.measured_at.map(|t| (chrono::Utc::now().timestamp() - t) / 60);
+    ui.label( RichText::new(self.texts.updated_ago(minutes, status.reachable)).color(Color32::GRAY) );
+  }
 
-    let title = "Дисплей";
+  /// measured and set-point temperature of every room, with buttons changing set-point and home mode
+  fn heating_group_table(&self, ui: &mut Ui, hs : &HeatingState ) {
     let text_color = Color32::from_rgb(242, 174, 73);
     let data_color = Color32::GREEN;
     let title_color = Color32::from_rgb(105, 209, 203);
 
+    ui.push_id("Heating Group Table", |ui| {
+        ui.vertical_centered(|ui| {
+            ui.group(|ui| {
+                    ui.label( RichText::new(self.texts.heating()).heading().color(title_color).size(20.0) )
+                      .on_hover_text(&hs.home_name);
+            });
+            ui.horizontal(|ui| {
+                for mode in [ThermMode::Schedule, ThermMode::Away, ThermMode::FrostGuard] {
+                    if ui.selectable_label(hs.mode == mode, self.texts.therm_mode(mode)).clicked() && hs.mode != mode {
+                        self.send_command( HomeCommand::SetThermMode( mode ) );
+                    }
+                }
+                if let Some( on ) = hs.boiler_on {
+                    let boiler_color = if on { Color32::RED } else { text_color };
+                    ui.label( RichText::new(self.texts.boiler(on)).color(boiler_color) );
+                }
+            });
+            let w = ui.available_width();
+            TableBuilder::new(ui)
+                .column( Column::exact(w*0.4) )
+                .column( Column::exact(w*0.2) )
+                .column( Column::exact(w*0.2) )
+                .column( Column::exact(w*0.2) )
+                .body(|body| {
+                    body.rows(50.0,  hs.rooms.len(), |row_index, mut row| {
+                        let room = &hs.rooms[row_index];
+                        let room_color = if room.reachable { data_color } else { Color32::GRAY };
+                        row.col(|ui| {
+                            ui.with_layout(Layout::left_to_right(Align::Center), |ui| {
+                                ui.label( RichText::new(&room.name).heading().color(text_color).size(30.0) );
+                            });
+                        });
+                        row.col(|ui| {
+                            ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                                let txt = room.measured_temperature.map(|t| format!("{:.1}", t)).unwrap_or_default();
+                                ui.label( RichText::new(txt).heading().color(room_color).size(30.0) );
+                            });
+                        });
+                        row.col(|ui| {
+                            ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                                // manual set-point stands out from scheduled one
+                                let setpoint_color = if room.manual { title_color } else { text_color };
+                                let txt = room.setpoint_temperature.map(|t| format!("→{:.1}", t)).unwrap_or_default();
+                                ui.label( RichText::new(txt).heading().color(setpoint_color).size(30.0) );
+                            });
+                        });
+                        row.col(|ui| {
+                            let Some( setpoint ) = room.setpoint_temperature else {
+                                return;
+                            };
+                            ui.with_layout(Layout::left_to_right(Align::Center), |ui| {
+                                if ui.button( RichText::new("−").size(30.0) ).clicked() {
+                                    self.send_command( HomeCommand::SetRoomSetpoint( room.id.clone(), (setpoint - SETPOINT_STEP).max(MIN_SETPOINT) ) );
+                                }
+                                if ui.button( RichText::new("+").size(30.0) ).clicked() {
+                                    self.send_command( HomeCommand::SetRoomSetpoint( room.id.clone(), (setpoint + SETPOINT_STEP).min(MAX_SETPOINT) ) );
+                                }
+                            });
+                        });
+                    });
+                });
+        });
+    });
+  }
+
+  /// tiles of different locations may have the same titles
+  fn tile_key(&self, tile : &str) -> String {
+    let location = self.locations.get(self.gui_state.location).map(|l| l.title.as_str()).unwrap_or_default();
+    format!("{}/{}", location, tile)
+  }
+
+  fn chart_measure(&self, tile : &str) -> Measure {
+    self.gui_state.chart_measures.get(&self.tile_key(tile)).copied().unwrap_or(Measure::Temperature)
+  }
+
+  /// sparkline of the last day or week, returns true if it's clicked
+  fn history_plot(&self, ui : &mut Ui, id : &str, series : Option<&Vec<(i64, f32)>>) -> bool {
+    let hours : i64 = if self.gui_state.history_week { 7 * 24 } else { 24 };
+    let now = chrono::Utc::now().timestamp();
+    let points : Vec<[f64; 2]> = series.map(|s| s.iter()
+            .filter(|(t, _)| now - t <= hours * 3600)
+            .map(|(t, v)| [(t - now) as f64 / 3600.0, *v as f64])
+            .collect())
+        .unwrap_or_default();
+
+    let text_color = Color32::from_rgb(242, 174, 73);
+    let response = plot::Plot::new( (id, "history") )
+        .height(60.0)
+        .show_axes([false, false])
+        .show_background(false)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .allow_scroll(false)
+        .allow_boxed_zoom(false)
+        .allow_double_click_reset(false)
+        .include_x( -(hours as f64) )
+        .include_x(0.0)
+        .show(ui, |plot_ui| plot_ui.line( plot::Line::new( plot::PlotPoints::from(points) ).color(Color32::GREEN) ));
+    ui.label( RichText::new(self.texts.history_period(self.gui_state.history_week)).color(text_color).small() );
+
+    response.response.clicked()
+  }
+
+  fn apply_chart_action(&mut self, tile : &str, action : ChartAction) {
+    match action {
+      ChartAction::Select( measure ) => {
+        self.gui_state.chart_measures.insert(self.tile_key(tile), measure);
+      },
+      ChartAction::TogglePeriod => self.gui_state.history_week = !self.gui_state.history_week,
+    }
+  }
+
+  /// value of continuous feature shown on the tile, requested one until display reports it
+  fn shown_value(&self, key : &DisplayKey, f : &FeatureState) -> Option<u16> {
+    match self.gui_state.pending_values.get(&(key.clone(), f.feature)) {
+      Some( (val, requested_at) ) if requested_at.elapsed() < PENDING_VALUE_TIMEOUT => Some( *val ),
+      _ => f.value,
+    }
+  }
+
+  /// sub-tile of every display, returns display and control pressed by user
+  fn display_group_table(&self, ui: &mut Ui, displays : &[DisplayState] ) -> Option<(DisplayKey, DisplayAction)> {
+    let mut action = None;
+    let title = "Дисплей";
+    let title_color = Color32::from_rgb(105, 209, 203);
+
     ui.push_id(title, |ui| {
         ui.vertical_centered(|ui| {
             ui.group(|ui| {
                     ui.label( RichText::new(title).heading().color(title_color).size(20.0) );
             });
+            for dd in displays {
+                if let Some( a ) = self.display_tile(ui, dd, displays.len() > 1) {
+                    action = Some( (dd.key.clone(), a) );
+                }
+            }
+        });
+    });
+
+    action
+  }
+
+  /// name of display is shown only if there are several ones
+  fn display_tile(&self, ui: &mut Ui, dd : &DisplayState, show_name : bool ) -> Option<DisplayAction> {
+    let mut action = None;
+    let (continuous, listed) : (Vec<&FeatureState>, Vec<&FeatureState>) = dd.features.iter().partition(|f| f.feature.is_continuous());
+
+    // continuous features, preset, then features with listed values
+    let mut name_texts = Vec::new();
+    let mut data_texts = Vec::new();
+    for f in &continuous {
+        name_texts.push( self.texts.display_feature(f.feature) );
+        data_texts.push( self.shown_value(&dd.key, f).map(|val| format!("{}", val)).unwrap_or_default() );
+    }
+    if !dd.presets.is_empty() {
+        name_texts.push( self.texts.preset() );
+        data_texts.push( dd.preset.as_ref().map(|pr| self.texts.show_preset(pr)).unwrap_or_default() );
+    }
+    for f in &listed {
+        name_texts.push( self.texts.display_feature(f.feature) );
+        let value = f.value.map(|val| match f.choices.iter().find(|(v, _)| *v == val) {
+            Some( (_, name) ) => name.clone(),
+            None => format!("{:#x}", val),
+        });
+        data_texts.push( value.unwrap_or_default() );
+    }
+
+    let text_color = Color32::from_rgb(242, 174, 73);
+    let data_color = Color32::GREEN;
+    // rows are lower if several displays share the tile
+    let (row_height, text_size) = if show_name { (40.0, 30.0) } else { (60.0, 40.0) };
+
+    ui.push_id(&dd.key, |ui| {
+        ui.vertical_centered(|ui| {
+            if show_name {
+                ui.label( RichText::new(&dd.name).heading().color(text_color).size(20.0) );
+            }
             let w = ui.available_width();
             TableBuilder::new(ui)
                 .column( Column::exact(w/2.) )
                 .column( Column::exact(w/2.) )
                 .body(|body| {
-                    body.rows(60.0,  name_texts.len(), |row_index, mut row| {
+                    body.rows(row_height,  name_texts.len(), |row_index, mut row| {
                         row.col(|ui| {
                             ui.with_layout(Layout::left_to_right(Align::Center), |ui| {
-                                ui.label( RichText::new(name_texts[row_index]).heading().color(text_color).size(40.0) );
+                                ui.label( RichText::new(name_texts[row_index]).heading().color(text_color).size(text_size) );
                             });
                         });
-                        if let Some( txt ) = data_texts.get(row_index) {
-                            row.col(|ui| {
-                                ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
-                                    ui.label( RichText::new(txt).heading().color(data_color).size(40.0) );
-                                 });
-                            });
-                       };
+                        row.col(|ui| {
+                            ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                                ui.label( RichText::new(&data_texts[row_index]).heading().color(data_color).size(text_size) );
+                             });
+                        });
                    });
                 });
+
+            if let Some( err ) = &dd.error {
+                ui.label( RichText::new(self.texts.display_error()).color(Color32::RED) )
+                  .on_hover_text(err);
+            }
+            ui.horizontal_wrapped(|ui| {
+                for name in &dd.presets {
+                    let selected = matches!(&dd.preset, Some( Preset::Named( n ) ) if n == name);
+                    if ui.selectable_label(selected, RichText::new(name).size(20.0)).clicked() {
+                        action = Some( DisplayAction::SetPreset( name.clone() ) );
+                    }
+                }
+            });
+            for f in &listed {
+                ui.horizontal_wrapped(|ui| {
+                    for (value, name) in &f.choices {
+                        if ui.selectable_label(f.value == Some( *value ), RichText::new(name).size(20.0)).clicked() {
+                            action = Some( DisplayAction::SetFeature( f.feature, *value ) );
+                        }
+                    }
+                });
+            }
+
+            for f in &continuous {
+                let (Some( mut value ), Some( max )) = (self.shown_value(&dd.key, f), f.max) else { continue; };
+                if self.gui_state.pending_values.contains_key(&(dd.key.clone(), f.feature)) {
+                    // to show display value again after timeout
+                    ui.ctx().request_repaint_after(PENDING_VALUE_TIMEOUT);
+                }
+                if continuous.len() > 1 {
+                    ui.label( RichText::new(self.texts.display_feature(f.feature)).color(text_color).size(20.0) );
+                }
+                ui.push_id(f.feature, |ui| {
+                    ui.horizontal(|ui| {
+                        let button_size = vec2(50.0, 50.0);
+                        if ui.add( Button::new( RichText::new("−").size(30.0) ).min_size(button_size) ).clicked() {
+                            action = Some( DisplayAction::SetFeature( f.feature, value.saturating_sub(FEATURE_STEP) ) );
+                        }
+                        // wide and thick enough for a finger
+                        ui.spacing_mut().slider_width = ui.available_width() - button_size.x - ui.spacing().item_spacing.x * 2.0;
+                        ui.spacing_mut().interact_size.y = button_size.y;
+                        let slider = ui.add( Slider::new(&mut value, 0..=max).show_value(false) );
+                        // DDC/CI is slow, so value is sent once dragging is over
+                        if slider.drag_released() || (slider.changed() && !slider.dragged()) {
+                            action = Some( DisplayAction::SetFeature( f.feature, value ) );
+                        }
+                        if ui.add( Button::new( RichText::new("+").size(30.0) ).min_size(button_size) ).clicked() {
+                            action = Some( DisplayAction::SetFeature( f.feature, (value + FEATURE_STEP).min(max) ) );
+                        }
+                    });
+                });
+            }
         });
     });
+
+    action
+  }
+
+  fn set_display_feature(&mut self, key : DisplayKey, feature : DisplayFeature, val : u16) {
+    if feature.is_continuous() {
+        self.gui_state.pending_values.insert((key.clone(), feature), (val, Instant::now()));
+    }
+    self.send_command( HomeCommand::SetDisplayFeature( key, feature, val ) );
   }
 
   fn show_trend(&self, ui : &mut Ui, trend : &Trend)
@@ -335,17 +780,36 @@ impl eframe::App for HomeDashboard {
     }
 
     if let Some( new_state ) = new_state {
-      if  self.state.bt_state.is_aeropex_connected != new_state.bt_state.is_aeropex_connected {
-        self.gui_state.aeropex_switch_state = new_state.bt_state.is_aeropex_connected;
-      }
-      if  self.state.bt_state.is_edifier_connected != new_state.bt_state.is_edifier_connected {
-        self.gui_state.edifier_switch_state = new_state.bt_state.is_edifier_connected;
+      for device in &new_state.bt_state.devices {
+        let was_connected = self.state.bt_state.devices.iter().find(|d| d.key == device.key).map(|d| d.is_connected);
+        if  was_connected != Some( device.is_connected ) {
+          self.gui_state.bt_switch_states.insert(device.key.clone(), device.is_connected);
+        }
       }
       self.state = new_state;
     }
 
+    // requested value is reached, or display doesn't follow it
+    let displays = &self.state.displays;
+    self.gui_state.pending_values.retain(|(key, feature), (val, requested_at)| {
+      let reported = displays.iter().find(|d| &d.key == key)
+        .and_then(|d| d.features.iter().find(|f| f.feature == *feature))
+        .and_then(|f| f.value);
+      reported != Some( *val ) && requested_at.elapsed() < PENDING_VALUE_TIMEOUT
+    });
+
     let Vec2 {x : frame_width, y : frame_height} = ctx.screen_rect().size();
     egui::CentralPanel::default().show(ctx, |ui| {
+      if self.locations.len() > 1 {
+          ui.horizontal(|ui| {
+              for (index, location) in self.locations.iter().enumerate() {
+                  if ui.selectable_label(self.gui_state.location == index, RichText::new(&location.title).size(20.0)).clicked() {
+                      self.gui_state.location = index;
+                  }
+              }
+          });
+      }
+
       Grid::new("unique grid")
        .min_col_width(frame_width / 6.0)
        .min_row_height(frame_height / 3.0)
@@ -354,36 +818,65 @@ impl eframe::App for HomeDashboard {
        .show(ui, |ui| {
          ui.end_row();
 
+         // copy, as tiles below change GUI state
+         let location_cfg = self.locations.get(self.gui_state.location).cloned();
+         let location = location_cfg.as_ref().and_then(|cfg| self.state.locations.iter().find(|l| l.title == cfg.title)).cloned();
+         let location = location.as_ref();
+         let rooms : Vec<String> = location_cfg.map(|cfg| cfg.rooms.iter().map(|r| r.title.clone()).collect()).unwrap_or_default();
+
          ui.add_visible(false, Separator::default());
-         let home_data =
-             if let Some( wd ) = &self.state.weather_data {
-                 Some ( AirQualityData {
-                     room_temperature : wd.room_temperature,
-                     room_humidity : wd.room_humidity,
-                     room_co2 : wd.room_co2,
-                     room_noise : wd.room_noise,
-                 })
-             } else {
-                None
-             };
-
-
-         self.outdoor_group_table(ui, &self.state.weather_data);
+         if let Some( action ) = self.outdoor_group_table(ui, location) {
+             self.apply_chart_action(OUTDOOR_TILE, action);
+         }
          self.bt_group(ui);
-         self.display_group_table(ui, &self.state.display_state);
+         match self.display_group_table(ui, &self.state.displays) {
+             Some( (key, DisplayAction::SetFeature( feature, val )) ) => self.set_display_feature(key, feature, val),
+             Some( (key, DisplayAction::SetPreset( name )) ) => self.send_command( HomeCommand::SetPreset( key, name ) ),
+             None => (),
+         }
          ui.end_row();
 
-         ui.add_visible(false, Separator::default());
-         self.home_group_table(ui,  "Дом", &home_data);
-         self.home_group_table(ui,  "Переговорка", &self.state.office_room_data);
-         self.home_group_table(ui,  "Детская", &self.state.child_room_data);
-         ui.end_row();
+         // same three tiles per row as above
+         let mut room_action = None;
+         for rooms in rooms.chunks(3) {
+             ui.add_visible(false, Separator::default());
+             for title in rooms {
+                 if let Some( action ) = self.home_group_table(ui, title, location) {
+                     room_action = Some( (title.clone(), action) );
+                 }
+             }
+             ui.end_row();
+         }
+         if let Some( (title, action) ) = room_action {
+             self.apply_chart_action(&title, action);
+         }
+
+         let rain = location.and_then(|l| l.weather_data.as_ref()).and_then(|wd| wd.rain.as_ref());
+         let wind = location.and_then(|l| l.weather_data.as_ref()).and_then(|wd| wd.wind.as_ref());
+         let heating = self.state.heating.as_ref();
+         if rain.is_some() || wind.is_some() || heating.is_some() {
+             ui.add_visible(false, Separator::default());
+             if let Some( rd ) = rain {
+                 self.rain_group_table(ui, rd);
+             }
+             if let Some( wd ) = wind {
+                 self.wind_group_table(ui, wd);
+             }
+             if let Some( hs ) = heating {
+                 self.heating_group_table(ui, hs);
+             }
+             ui.end_row();
+         }
       });
 
       if ui.ctx().input( |i| i.key_pressed(Key::Q) )   {
         frame.close();
       }
     });
+
+    if self.gui_state.show_discovery {
+      self.discovery_window(ctx);
+    }
   }
 
 }
@@ -1,4 +1,5 @@
-use crate::worker::ddc_display::Preset;
+use crate::worker::ddc_display::{Preset, DisplayFeature};
+use crate::interface::{AdapterState, NetatmoErrorKind, ThermMode};
 
 #[derive(PartialEq)]
 pub enum Language {
@@ -35,21 +36,158 @@ impl Texts {
      self.select("Давление", "Pressure")
  }
 
- pub fn brightness<'a>(&self) -> &'a str {
-     self.select("Яркость", "Brightness")
- }
-
  pub fn preset<'a>(&self) -> &'a str {
      self.select("Режим", "Preset")
  }
 
+ pub fn display_feature<'a>(&self, feature : DisplayFeature) -> &'a str {
+     match feature {
+         DisplayFeature::Brightness => self.select("Яркость", "Brightness"),
+         DisplayFeature::Contrast => self.select("Контраст", "Contrast"),
+         DisplayFeature::Volume => self.select("Громкость", "Volume"),
+         DisplayFeature::ColorPreset => self.select("Цвет", "Color"),
+         DisplayFeature::Input => self.select("Вход", "Input"),
+         DisplayFeature::PowerMode => self.select("Питание", "Power"),
+     }
+ }
+
+ pub fn display_error<'a>(&self) -> &'a str {
+     self.select("Дисплей не выполнил команду", "Display command failed")
+ }
+
+ pub fn bt_unavailable<'a>(&self) -> &'a str {
+     self.select("Не сопряжено", "Not paired")
+ }
+
+ pub fn bt_reconnecting<'a>(&self) -> &'a str {
+     self.select("Переподключение…", "Reconnecting…")
+ }
+
+ pub fn bt_discover<'a>(&self) -> &'a str {
+     self.select("Поиск устройств", "Discover devices")
+ }
+
+ pub fn bt_searching<'a>(&self) -> &'a str {
+     self.select("Идёт поиск…", "Searching…")
+ }
+
+ pub fn bt_pair<'a>(&self) -> &'a str {
+     self.select("Сопрячь", "Pair")
+ }
+
+ pub fn bt_adapter_state<'a>(&self, adapter : &AdapterState) -> &'a str {
+     if !adapter.present {
+         self.select("Адаптер не найден", "No adapter")
+     } else if adapter.blocked {
+         self.select("Адаптер заблокирован", "Adapter is blocked")
+     } else if adapter.powered {
+         self.select("Адаптер включен", "Adapter is on")
+     } else {
+         self.select("Адаптер выключен", "Adapter is off")
+     }
+ }
+
+ pub fn audio_output<'a>(&self) -> &'a str {
+     self.select("Звук здесь", "Audio output")
+ }
+
+ pub fn turn_on<'a>(&self) -> &'a str {
+     self.select("Включить", "Turn on")
+ }
+
+ pub fn turn_off<'a>(&self) -> &'a str {
+     self.select("Выключить", "Turn off")
+ }
+
+ pub fn rain<'a>(&self) -> &'a str {
+     self.select("Осадки", "Rain")
+ }
+
+ pub fn rain_last_hour<'a>(&self) -> &'a str {
+     self.select("За час", "Last hour")
+ }
+
+ pub fn rain_last_day<'a>(&self) -> &'a str {
+     self.select("За сутки", "Last 24h")
+ }
+
+ pub fn wind<'a>(&self) -> &'a str {
+     self.select("Ветер", "Wind")
+ }
+
+ pub fn wind_strength<'a>(&self) -> &'a str {
+     self.select("Скорость", "Speed")
+ }
+
+ pub fn wind_gust<'a>(&self) -> &'a str {
+     self.select("Порывы", "Gusts")
+ }
+
+ pub fn wind_direction<'a>(&self) -> &'a str {
+     self.select("Откуда", "From")
+ }
+
+ /// one of 8 compass points for angle in degrees, wind angle is where it blows from
+ pub fn compass_point<'a>(&self, angle : i32) -> &'a str {
+     const POINTS : [(&str, &str); 8] = [("С", "N"), ("СВ", "NE"), ("В", "E"), ("ЮВ", "SE"), ("Ю", "S"), ("ЮЗ", "SW"), ("З", "W"), ("СЗ", "NW")];
+     let index = ((angle.rem_euclid(360) as f32 / 45.0).round() as usize) % POINTS.len();
+     let (ru, en) = POINTS[index];
+     self.select(ru, en)
+ }
+
+ pub fn history_period<'a>(&self, week : bool) -> &'a str {
+     if week {
+         self.select("за неделю", "last week")
+     } else {
+         self.select("за сутки", "last 24h")
+     }
+ }
+
+ pub fn netatmo_error<'a>(&self, kind : &NetatmoErrorKind) -> &'a str {
+     match kind {
+         NetatmoErrorKind::Network => self.select("Нет связи с Netatmo", "Netatmo is unreachable"),
+         NetatmoErrorKind::Server => self.select("Сбой сервера Netatmo", "Netatmo server error"),
+         NetatmoErrorKind::Auth => self.select("Нужна авторизация Netatmo", "Netatmo authorization needed"),
+         NetatmoErrorKind::RateLimit => self.select("Лимит запросов Netatmo", "Netatmo rate limit"),
+     }
+ }
+
+ pub fn updated_ago(&self, minutes : Option<i64>, reachable : bool) -> String {
+     match (minutes, reachable) {
+         (_, false) => String::from(self.select("Нет связи с модулем", "Module is unreachable")),
+         (None, true) => String::from(self.select("Нет данных", "No data")),
+         (Some( m ), true) if m < 60 => format!("{} {} {}", self.select("Обновлено", "Updated"), m, self.select("мин назад", "min ago")),
+         (Some( m ), true) => format!("{} {} {}", self.select("Обновлено", "Updated"), m / 60, self.select("ч назад", "h ago")),
+     }
+ }
+
+ pub fn heating<'a>(&self) -> &'a str {
+     self.select("Отопление", "Heating")
+ }
+
+ pub fn therm_mode<'a>(&self, mode : ThermMode) -> &'a str {
+     match mode {
+         ThermMode::Schedule => self.select("Расписание", "Schedule"),
+         ThermMode::Away => self.select("Не дома", "Away"),
+         ThermMode::FrostGuard => self.select("Без заморозки", "Frost guard"),
+     }
+ }
+
+ pub fn boiler<'a>(&self, on : bool) -> &'a str {
+     if on {
+         self.select("Котёл греет", "Boiler is on")
+     } else {
+         self.select("Котёл выключен", "Boiler is off")
+     }
+ }
+
  pub fn show_preset(&self, p : &Preset) -> String {
      match p {
-         Preset::Standard => String::from(self.select("Стандартный", "Standard")),
-         Preset::Comfort => String::from(self.select("Комфортный", "Comfort")),
-         Preset::Game => String::from(self.select("Игровой", "Game")),
-         Preset::Movie => String::from(self.select("Просмотра фильма", "Movie")),
-         Preset::Unknown{val_dc, val_f0} => format!("{} {:#x} {:#x}", self.select("Неизвесный", "Unknown"),  val_dc, val_f0),
+         Preset::Named( name ) => name.clone(),
+         Preset::Unknown( values ) => {
+             let values : Vec<String> = values.iter().map(|(code, val)| format!("{:#x}={:#x}", code, val)).collect();
+             format!("{} {}", self.select("Неизвесный", "Unknown"), values.join(" "))
+         },
      }
  }
 
@@ -1,21 +1,92 @@
 use serde::{Serialize, Deserialize};
 use netatmo_connect::ConnectConfig;
 use std::option::Option;
-use crate::worker::ddc_display::DisplayState;
+use std::collections::HashMap;
+use crate::worker::ddc_display::{DisplayState, DisplayFeature};
+
+pub const CONFIGURATION_NAME : &str = "home-dashboard";
 
 #[derive(Default, Debug, Clone)]
 pub struct HomeState {
   pub bt_state : BluetoothState,
+  // in order of NetatmoConfig::locations
+  pub locations : Vec<LocationState>,
+  // Some while Netatmo requests fail, data above is the last successfully received
+  pub netatmo_error : Option<NetatmoErrorState>,
+  // None if heating is not configured or not received yet
+  pub heating : Option<HeatingState>,
+  // configured displays first, in order of DisplaysConfig::displays
+  pub displays : Vec<DisplayState>,
+}
+
+// weather station with its rooms, i.e. home or country house
+#[derive(Default, Debug, Clone)]
+pub struct LocationState {
+  pub title : String,
   pub weather_data : Option<WeatherData>,
-  pub child_room_data : Option<AirQualityData>,
-  pub office_room_data : Option<AirQualityData>,
-  pub display_state : Option<DisplayState>,
+  // by RoomConfig::title
+  pub room_data : HashMap<String, AirQualityData>,
+  pub outdoor_history : MeasureHistory,
+  // by RoomConfig::title
+  pub room_history : HashMap<String, MeasureHistory>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceKey(pub String);
+
+// serial number of DDC display, or its model and connection if there is no serial
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DisplayKey(pub String);
+
 #[derive(Default, Debug, Clone)]
 pub struct BluetoothState {
-  pub is_aeropex_connected : bool,
-  pub is_edifier_connected : bool,
+  pub adapter : AdapterState,
+  pub devices : Vec<BluetoothDeviceState>,
+  // PulseAudio/PipeWire default sink
+  pub default_sink : Option<String>,
+  // devices found during discovery which are not in configuration yet
+  pub discovered : Vec<DiscoveredDevice>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct AdapterState {
+  pub present : bool,
+  pub powered : bool,
+  pub discovering : bool,
+  // rfkill soft or hard block
+  pub blocked : bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+  pub mac : String,
+  pub name : Option<String>,
+  pub rssi : Option<i16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BluetoothDeviceState {
+  pub key : DeviceKey,
+  pub name : String,
+  pub icon : Option<String>,
+  pub group : Option<String>,
+  // only one device of exclusive group could be connected at a time
+  pub is_exclusive : bool,
+  // false if device is not known to BlueZ, i.e. it's not paired yet
+  pub is_available : bool,
+  pub is_connected : bool,
+  // default audio sink belongs to this device
+  pub is_audio_output : bool,
+  // battery percentage reported via BlueZ Battery1 interface, if device supports it
+  pub battery : Option<u8>,
+  // Some while worker is trying to restore connection according to ReconnectPolicy
+  pub reconnect : Option<ReconnectState>,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ReconnectState {
+  pub attempt : u32,
+  pub last_error : Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -33,7 +104,19 @@ pub struct WeatherData {
   pub room_noise : i32,
   pub pressure : f32,
   pub pressure_trend : Option<Trend>,
+  // of main module, room_* and pressure
+  pub status : ReadingStatus,
   pub outdoor_weather : Option<OutdoorWeatherData>,
+  pub rain : Option<RainData>,
+  pub wind : Option<WindData>,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ReadingStatus {
+  // unix time of measurement, None if module has never reported
+  pub measured_at : Option<i64>,
+  // module is connected to station or to Netatmo, otherwise values are the last known ones
+  pub reachable : bool,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -41,6 +124,39 @@ pub struct OutdoorWeatherData {
   pub temperature : f32,
   pub temperature_trend : Option<Trend>,
   pub humidity : i32,
+  pub status : ReadingStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Measure {
+  Temperature,
+  Humidity,
+  CO2,
+  Pressure,
+}
+
+// last week of measurements at 30 minutes scale
+#[derive(Default, Debug, Clone)]
+pub struct MeasureHistory {
+  // unix time and value, oldest first
+  pub series : HashMap<Measure, Vec<(i64, f32)>>,
+}
+
+// rain gauge, mm
+#[derive(Default, Debug, Clone)]
+pub struct RainData {
+  pub last_hour : f32,
+  pub last_day : f32,
+  pub status : ReadingStatus,
+}
+
+// wind gauge, km/h and degrees
+#[derive(Default, Debug, Clone)]
+pub struct WindData {
+  pub strength : i32,
+  pub angle : i32,
+  pub gust_strength : i32,
+  pub status : ReadingStatus,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -48,25 +164,377 @@ pub struct AirQualityData {
   pub room_temperature : f32,
   pub room_humidity : i32,
   pub room_co2 : i32,
-  pub room_noise : i32,
+  // additional indoor modules have no microphone
+  pub room_noise : Option<i32>,
+  pub status : ReadingStatus,
+}
+
+// Netatmo Energy home with thermostat and valves
+#[derive(Debug, Clone)]
+pub struct HeatingState {
+  pub home_id : String,
+  pub home_name : String,
+  pub mode : ThermMode,
+  // in order of Netatmo app
+  pub rooms : Vec<HeatingRoomState>,
+  // None if there is no boiler relay in the home
+  pub boiler_on : Option<bool>,
+}
+
+// °C, bounds of room set-point accepted by thermostats and valves
+pub const MIN_SETPOINT : f32 = 7.0;
+pub const MAX_SETPOINT : f32 = 30.0;
+
+#[derive(Debug, Clone)]
+pub struct HeatingRoomState {
+  pub id : String,
+  pub name : String,
+  pub measured_temperature : Option<f32>,
+  pub setpoint_temperature : Option<f32>,
+  // set-point is changed by hand, not by schedule
+  pub manual : bool,
+  pub reachable : bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThermMode {
+  Schedule,
+  Away,
+  FrostGuard,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetatmoErrorKind {
+  // connection refused, timeout, broken response and the like
+  Network,
+  // 5xx, other HTTP errors without token error code, unexpected answers
+  Server,
+  // token is rejected, it's requested again
+  Auth,
+  // too many requests for the application or user
+  RateLimit,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetatmoErrorState {
+  pub kind : NetatmoErrorKind,
+  pub message : String,
+  // number of failed attempts in a row
+  pub attempt : u32,
 }
 
 #[derive(Debug)]
 pub enum HomeCommand {
-  ConnectAeropex,
-  DisconnectAeropex,
-  ConnectEdifier,
-  DisconnectEdifier,
+  Connect(DeviceKey),
+  Disconnect(DeviceKey),
+  StartDiscovery,
+  StopDiscovery,
+  // pair, trust and add to configured devices, MAC is taken from DiscoveredDevice
+  Pair(String),
+  SetAdapterPowered(bool),
+  // poll Netatmo right away, i.e. to retry after an error
+  RefreshNetatmo,
+  // HeatingRoomState::id and temperature, manual until the end of home's default duration
+  SetRoomSetpoint(String, f32),
+  SetThermMode(ThermMode),
+  // VCP value of the feature, 0..=FeatureState::max for continuous ones, otherwise one of FeatureState::choices
+  SetDisplayFeature(DisplayKey, DisplayFeature, u16),
+  // PresetConfig::name
+  SetPreset(DisplayKey, String),
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct HomeDashboardConfig {
   pub connect_config : ConnectConfig,
+  #[serde(default)]
+  pub netatmo_config : NetatmoConfig,
   pub bt_config : BluetoothConfig,
+  #[serde(default)]
+  pub displays_config : DisplaysConfig,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct NetatmoConfig {
+  // GUI switches between them if there are several ones
+  pub locations : Vec<LocationConfig>,
+  // older readings are greyed out, stations upload every 10 minutes
+  pub stale_after_minutes : u32,
+  // base address of weather API, i.e. "http://127.0.0.1:8080/api" for netatmo-mock binary
+  pub api_url : String,
+  // base address of OAuth server which approves authorization without user, i.e. "http://127.0.0.1:8080/oauth2"
+  // for netatmo-mock binary; if None, netatmo_connect authorizes at api.netatmo.com
+  pub auth_url : Option<String>,
+  // Netatmo Energy thermostat and valves, requires read_thermostat and write_thermostat scopes
+  pub heating : Option<HeatingConfig>,
+  // seconds, polling follows uploads of stations but never more often than this
+  pub min_poll_interval : u64,
+  // Netatmo allows 500 requests per hour for a user, the budget is lowered further on quota errors
+  pub requests_per_hour : u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct HeatingConfig {
+  // id of home from homesdata, first home with rooms if None
+  #[serde(default)]
+  pub home_id : Option<String>,
+}
+
+impl Default for NetatmoConfig {
+  fn default() -> Self {
+    NetatmoConfig {
+      locations : vec![ LocationConfig {
+        title : String::from("Дом"),
+        station : RoomSource::First,
+        rooms : vec![
+          RoomConfig { title : String::from("Дом"), source : RoomSource::First, module_type : RoomModuleType::WeatherStation },
+          RoomConfig { title : String::from("Переговорка"), source : RoomSource::StationName( String::from("Переговорка") ), module_type : RoomModuleType::HomeCoach },
+          RoomConfig { title : String::from("Детская"), source : RoomSource::StationName( String::from("Детская") ), module_type : RoomModuleType::HomeCoach },
+        ],
+      }],
+      stale_after_minutes : 30,
+      api_url : String::from("https://api.netatmo.com/api"),
+      auth_url : None,
+      heating : None,
+      min_poll_interval : 60,
+      requests_per_hour : 400,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LocationConfig {
+  pub title : String,
+  // weather station whose outdoor module, rain and wind gauges are shown
+  pub station : RoomSource,
+  // tiles are shown in the same order
+  pub rooms : Vec<RoomConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoomConfig {
+  pub title : String,
+  pub source : RoomSource,
+  pub module_type : RoomModuleType,
+}
+
+/// how to find Netatmo device of the room or location
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RoomSource {
+  // MAC-like id, i.e. "70:ee:50:00:00:00"
+  DeviceId(String),
+  // station_name (module_name for indoor modules) as it's set in Netatmo app
+  StationName(String),
+  // first device of the module type, for homes with a single one
+  First,
+}
+
+impl RoomSource {
+  pub fn matches(&self, id : &str, station_name : &str) -> bool {
+    match self {
+      RoomSource::DeviceId( v ) => v == id,
+      RoomSource::StationName( v ) => v == station_name,
+      RoomSource::First => true,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RoomModuleType {
+  // indoor (main) module of weather station, NAMain
+  WeatherStation,
+  // additional indoor module of weather station, NAModule4
+  IndoorModule,
+  // Healthy Home Coach, NHC
+  HomeCoach,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DisplaysConfig {
+  // displays which are not here are shown by model name, without presets
+  pub displays : Vec<DisplayConfig>,
+}
+
+impl Default for DisplaysConfig {
+  fn default() -> Self {
+    DisplaysConfig {
+      displays : vec![ DisplayConfig {
+        id : String::from("DELL U3421WE"),
+        name : String::from("Дисплей"),
+        // 0xDC is display mode, 0xF0 is Dell comfort view
+        presets : vec![
+          PresetConfig::new("Стандартный", &[(0xDC, 0), (0xF0, 0)]),
+          PresetConfig::new("Комфортный", &[(0xDC, 0), (0xF0, 0xC)]),
+          PresetConfig::new("Просмотра фильма", &[(0xDC, 3), (0xF0, 0)]),
+          PresetConfig::new("Игровой", &[(0xDC, 5), (0xF0, 0)]),
+        ],
+        inputs : vec![
+          InputConfig { name : String::from("Laptop USB-C"), value : 0x1B },
+          InputConfig { name : String::from("Desktop DP"), value : 0x0F },
+        ],
+        poll_interval_ms : 1000,
+      }],
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DisplayConfig {
+  // serial number or model name reported by display, i.e. "DELL U3421WE"
+  pub id : String,
+  pub name : String,
+  // manufacturer-specific, in order of buttons; presets writing registers which display doesn't support are dropped
+  #[serde(default)]
+  pub presets : Vec<PresetConfig>,
+  // names of input sources in order of buttons, MCCS names from capabilities are used if there are none
+  #[serde(default)]
+  pub inputs : Vec<InputConfig>,
+  #[serde(default = "default_display_poll_interval")]
+  pub poll_interval_ms : u64,
+}
+
+fn default_display_poll_interval() -> u64 {
+  1000
+}
+
+impl DisplayConfig {
+  pub fn unconfigured(name : String) -> Self {
+    DisplayConfig { id : name.clone(), name, presets : Vec::new(), inputs : Vec::new(), poll_interval_ms : default_display_poll_interval() }
+  }
+}
+
+/// preset is on when all of its registers have these values, setting it writes them in order
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PresetConfig {
+  pub name : String,
+  pub registers : Vec<VcpSetting>,
+}
+
+impl PresetConfig {
+  pub fn new(name : &str, registers : &[(u8, u16)]) -> Self {
+    PresetConfig {
+      name : String::from(name),
+      registers : registers.iter().map(|&(code, value)| VcpSetting { code, value }).collect(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VcpSetting {
+  // VCP code, i.e. 0xDC
+  pub code : u8,
+  pub value : u16,
+}
+
+/// input source of display, VCP 0x60
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InputConfig {
+  // i.e. "Desktop DP"
+  pub name : String,
+  // MCCS value, i.e. 0x0F for DisplayPort-1, 0x11 for HDMI-1, Dell uses 0x1B for USB-C
+  pub value : u16,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct BluetoothConfig {
-  pub aeropex_mac : String,
-  pub edifier_mac : String,
+  #[serde(default)]
+  pub backend : BluetoothBackendKind,
+  #[serde(default)]
+  pub devices : Vec<BluetoothDeviceConfig>,
+  // groups (see BluetoothDeviceConfig::group) where connecting one device disconnects the others,
+  // i.e. speakers and headset sharing the same audio output
+  #[serde(default)]
+  pub exclusive_groups : Vec<String>,
+  #[serde(default)]
+  pub audio : AudioConfig,
+  // configuration before device list was introduced, moved into devices by migrate_legacy_macs
+  #[serde(default, skip_serializing)]
+  pub aeropex_mac : Option<String>,
+  #[serde(default, skip_serializing)]
+  pub edifier_mac : Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AudioConfig {
+  // make sink of just connected device the default one
+  pub set_default_sink : bool,
+  // also move already playing streams to it
+  pub move_streams : bool,
+}
+
+impl Default for AudioConfig {
+  fn default() -> Self {
+    AudioConfig { set_default_sink : true, move_streams : false }
+  }
+}
+
+impl BluetoothConfig {
+  /// turns aeropex_mac and edifier_mac into devices, returns true if there was something to migrate
+  pub fn migrate_legacy_macs(&mut self) -> bool {
+    let legacy = [
+      (self.aeropex_mac.take(), "AEROPEX", "headphones"),
+      (self.edifier_mac.take(), "EDIFIER", "speaker"),
+    ];
+    let mut migrated = false;
+    for (mac, name, icon) in legacy {
+      let Some( mac ) = mac else { continue; };
+      migrated = true;
+      if self.devices.iter().any(|d| d.mac.eq_ignore_ascii_case(&mac)) {
+        continue;
+      }
+      self.devices.push( BluetoothDeviceConfig {
+        name : String::from(name),
+        mac,
+        icon : Some( String::from(icon) ),
+        ..BluetoothDeviceConfig::default()
+      });
+    }
+    migrated
+  }
+
+  pub fn is_exclusive(&self, group : &Option<String>) -> bool {
+    match group {
+      Some( group ) => self.exclusive_groups.contains(group),
+      None => false,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub enum BluetoothBackendKind {
+  #[default]
+  Bluez,
+  // in-memory devices, for working on dashboard without bluetooth hardware
+  Fake,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct BluetoothDeviceConfig {
+  pub name : String,
+  pub mac : String,
+  // name of svg file in resources without extension, i.e. "headphones"
+  #[serde(default)]
+  pub icon : Option<String>,
+  #[serde(default)]
+  pub group : Option<String>,
+  #[serde(default)]
+  pub reconnect : ReconnectPolicy,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub enum ReconnectPolicy {
+  // connect only by button press
+  #[default]
+  Manual,
+  // reconnect whenever device is disconnected
+  KeepConnected,
+  // connect device as soon as it's powered on, but only within [from_hour, to_hour) local time,
+  // from_hour greater than to_hour means overnight range, i.e. from 22 to 6
+  WorkingHours { from_hour : u32, to_hour : u32 },
+}
+
+impl BluetoothDeviceConfig {
+  pub fn key(&self) -> DeviceKey {
+    DeviceKey( self.name.clone() )
+  }
 }
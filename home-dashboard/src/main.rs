@@ -6,12 +6,12 @@ use confy;
 use eframe::egui;
 use env_logger;
 use gui::HomeDashboard;
-use interface::HomeDashboardConfig;
+use interface::{HomeDashboardConfig, CONFIGURATION_NAME};
 
 fn main() {
   env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 
-  let configuration_name = "home-dashboard";
+  let configuration_name = CONFIGURATION_NAME;
   let configuration_path = confy::get_configuration_file_path(configuration_name, None);
   if let Err ( e ) = configuration_path {
       log::error!("Failed to obtain configuration path for {}. {:?}. Exiting.", configuration_name, e);
@@ -26,7 +26,14 @@ fn main() {
       log::error!("Failed to load configuration from {}. {:?}. Exiting.", configuration_path.display(), e);
       return;
   }
-  let cfg : HomeDashboardConfig = cfg.unwrap();
+  let mut cfg : HomeDashboardConfig = cfg.unwrap();
+
+  if cfg.bt_config.migrate_legacy_macs() {
+      log::info!("Moving aeropex_mac and edifier_mac into bluetooth device list");
+      if let Err( e ) = confy::store(configuration_name, None, &cfg) {
+          log::warn!("Failed to store migrated configuration to {}. {:?}", configuration_path.display(), e);
+      }
+  }
 
   let mut native_options = eframe::NativeOptions::default();
   native_options.fullscreen = true;
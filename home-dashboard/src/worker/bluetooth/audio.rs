@@ -0,0 +1,222 @@
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bluez_async::MacAddress;
+
+const SINK_WAIT_TIMEOUT : Duration = Duration::from_secs(10);
+const SINK_WAIT_STEP : Duration = Duration::from_millis(500);
+
+/// runs external program and returns its stdout
+pub trait CommandRunner : Send + Sync {
+  fn run(&self, program : &str, args : &[&str]) -> Result<String, String>;
+}
+
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+  fn run(&self, program : &str, args : &[&str]) -> Result<String, String> {
+      let output = Command::new(program).args(args).output().map_err(|e| format!("Failed to run {} : {}", program, e))?;
+      if !output.status.success() {
+          return Err( format!("{} {:?} failed with {} : {}", program, args, output.status, String::from_utf8_lossy(&output.stderr).trim()) );
+      }
+      Ok( String::from_utf8_lossy(&output.stdout).into_owned() )
+  }
+}
+
+/// pretends to be pactl with a sink for each added device, for running dashboard without audio hardware
+#[derive(Default)]
+pub struct FakeCommandRunner {
+  sinks : Mutex<Vec<String>>,
+  default_sink : Mutex<String>,
+  // ids of playing streams
+  sink_inputs : Mutex<Vec<u32>>,
+  // arguments of every pactl call, in order
+  calls : Mutex<Vec<Vec<String>>>,
+}
+
+impl FakeCommandRunner {
+  /// PipeWire style sink
+  pub fn add_bluetooth_sink(&self, mac : &MacAddress) {
+      self.add_sink( &format!("bluez_output.{}.1", mac_to_sink_part(mac)) );
+  }
+
+  pub fn add_sink(&self, name : &str) {
+      self.sinks.lock().unwrap().push( name.to_string() );
+  }
+
+  #[cfg(test)]
+  pub fn add_sink_input(&self, id : u32) {
+      self.sink_inputs.lock().unwrap().push(id);
+  }
+
+  #[cfg(test)]
+  pub fn calls(&self) -> Vec<Vec<String>> {
+      self.calls.lock().unwrap().clone()
+  }
+}
+
+impl CommandRunner for FakeCommandRunner {
+  fn run(&self, program : &str, args : &[&str]) -> Result<String, String> {
+      if program != "pactl" {
+          return Err( format!("Fake runner doesn't know {}", program) );
+      }
+      self.calls.lock().unwrap().push( args.iter().map(|a| a.to_string()).collect() );
+      match args {
+          ["list", "short", "sinks"] => {
+              let sinks = self.sinks.lock().unwrap();
+              Ok( sinks.iter().enumerate().map(|(i, s)| format!("{}\t{}\tPipeWire\ts16le 2ch 48000Hz\tSUSPENDED\n", i, s)).collect() )
+          },
+          ["list", "short", "sink-inputs"] => {
+              let inputs = self.sink_inputs.lock().unwrap();
+              Ok( inputs.iter().map(|id| format!("{}\t0\t{}\tPipeWire\tfloat32le 2ch 48000Hz\n", id, id + 100)).collect() )
+          },
+          ["get-default-sink"] => Ok( format!("{}\n", self.default_sink.lock().unwrap()) ),
+          ["set-default-sink", sink] => {
+              *self.default_sink.lock().unwrap() = sink.to_string();
+              Ok( String::new() )
+          },
+          ["move-sink-input", id, sink] if self.sinks.lock().unwrap().iter().any(|s| s == sink) => {
+              let id = id.parse::<u32>().map_err(|e| e.to_string())?;
+              if self.sink_inputs.lock().unwrap().contains(&id) { Ok( String::new() ) } else { Err( format!("No sink input {}", id) ) }
+          },
+          _ => Err( format!("Fake pactl doesn't support {:?}", args) ),
+      }
+  }
+}
+
+/// PulseAudio/PipeWire sinks control via pactl
+#[derive(Clone)]
+pub struct AudioSinks {
+  runner : Arc<dyn CommandRunner>,
+}
+
+impl AudioSinks {
+  pub fn new(runner : Arc<dyn CommandRunner>) -> Self {
+      AudioSinks { runner }
+  }
+
+  async fn pactl(&self, args : &[&str]) -> Result<String, String> {
+      let runner = self.runner.clone();
+      let args : Vec<String> = args.iter().map(|a| a.to_string()).collect();
+      tokio::task::spawn_blocking(move || {
+          let args : Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+          runner.run("pactl", &args)
+      }).await.map_err(|e| e.to_string())?
+  }
+
+  pub async fn default_sink(&self) -> Result<String, String> {
+      Ok( self.pactl(&["get-default-sink"]).await?.trim().to_string() )
+  }
+
+  /// sink of bluetooth device, i.e. bluez_output.00_11_22_33_44_55.1 (PipeWire) or bluez_sink.00_11_22_33_44_55.a2dp_sink (PulseAudio)
+  pub async fn find_bluetooth_sink(&self, mac : &MacAddress) -> Result<Option<String>, String> {
+      let sinks = self.pactl(&["list", "short", "sinks"]).await?;
+      Ok( sinks.lines()
+          .filter_map(|line| line.split('\t').nth(1))
+          .find(|name| AudioSinks::is_sink_of(name, mac))
+          .map(|name| name.to_string()) )
+  }
+
+  /// sink is created by audio server a bit later than bluetooth connection
+  pub async fn wait_bluetooth_sink(&self, mac : &MacAddress) -> Result<String, String> {
+      let mut waited = Duration::ZERO;
+      loop {
+          if let Some( sink ) = self.find_bluetooth_sink(mac).await? {
+              return Ok( sink );
+          }
+          if waited >= SINK_WAIT_TIMEOUT {
+              return Err( format!("Sink for {} didn't appear in {:?}", mac, SINK_WAIT_TIMEOUT) );
+          }
+          tokio::time::sleep(SINK_WAIT_STEP).await;
+          waited += SINK_WAIT_STEP;
+      }
+  }
+
+  pub async fn set_default_sink(&self, sink : &str) -> Result<(), String> {
+      self.pactl(&["set-default-sink", sink]).await.map(|_| ())
+  }
+
+  /// moves currently playing streams to the sink, new streams follow default sink by themselves
+  pub async fn move_streams(&self, sink : &str) -> Result<(), String> {
+      let inputs = self.pactl(&["list", "short", "sink-inputs"]).await?;
+      for id in inputs.lines().filter_map(|line| line.split('\t').next()).filter(|id| !id.is_empty()) {
+          if let Err( e ) = self.pactl(&["move-sink-input", id, sink]).await {
+              log::warn!("Failed to move stream {} to {} : {}", id, sink, e);
+          }
+      }
+      Ok(())
+  }
+
+  pub fn is_sink_of(sink : &str, mac : &MacAddress) -> bool {
+      sink.starts_with("bluez_") && sink.contains(&mac_to_sink_part(mac))
+  }
+}
+
+fn mac_to_sink_part(mac : &MacAddress) -> String {
+  mac.to_string().replace(':', "_")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str::FromStr;
+
+  const MAC : &str = "AA:BB:CC:DD:EE:01";
+
+  fn sinks(names : &[&str]) -> (AudioSinks, Arc<FakeCommandRunner>) {
+      let runner = Arc::new( FakeCommandRunner::default() );
+      runner.add_sink("alsa_output.pci-0000_00_1f.3.analog-stereo");
+      for name in names {
+          runner.add_sink(name);
+      }
+      (AudioSinks::new(runner.clone()), runner)
+  }
+
+  #[test]
+  fn sink_of_device() {
+      let mac = MacAddress::from_str(MAC).unwrap();
+      assert!(AudioSinks::is_sink_of("bluez_output.AA_BB_CC_DD_EE_01.1", &mac));
+      assert!(AudioSinks::is_sink_of("bluez_output.AA_BB_CC_DD_EE_01.a2dp-sink", &mac));
+      assert!(AudioSinks::is_sink_of("bluez_sink.AA_BB_CC_DD_EE_01.a2dp_sink", &mac));
+      assert!(!AudioSinks::is_sink_of("bluez_sink.AA_BB_CC_DD_EE_02.a2dp_sink", &mac));
+      assert!(!AudioSinks::is_sink_of("alsa_output.pci-0000_00_1f.3.analog-stereo", &mac));
+  }
+
+  #[tokio::test]
+  async fn find_pipewire_sink() {
+      let (audio, _) = sinks(&["bluez_output.AA_BB_CC_DD_EE_02.1", "bluez_output.AA_BB_CC_DD_EE_01.1"]);
+      let mac = MacAddress::from_str(MAC).unwrap();
+      assert_eq!(audio.find_bluetooth_sink(&mac).await, Ok( Some( String::from("bluez_output.AA_BB_CC_DD_EE_01.1") ) ));
+  }
+
+  #[tokio::test]
+  async fn find_pulseaudio_sink() {
+      let (audio, _) = sinks(&["bluez_sink.AA_BB_CC_DD_EE_01.a2dp_sink"]);
+      let mac = MacAddress::from_str(MAC).unwrap();
+      assert_eq!(audio.find_bluetooth_sink(&mac).await, Ok( Some( String::from("bluez_sink.AA_BB_CC_DD_EE_01.a2dp_sink") ) ));
+  }
+
+  #[tokio::test]
+  async fn no_sink_of_device() {
+      let (audio, _) = sinks(&["bluez_output.AA_BB_CC_DD_EE_02.1"]);
+      let mac = MacAddress::from_str(MAC).unwrap();
+      assert_eq!(audio.find_bluetooth_sink(&mac).await, Ok( None ));
+  }
+
+  #[tokio::test]
+  async fn move_every_stream() {
+      let sink = "bluez_output.AA_BB_CC_DD_EE_01.1";
+      let (audio, runner) = sinks(&[sink]);
+      runner.add_sink_input(12);
+      runner.add_sink_input(15);
+
+      audio.move_streams(sink).await.unwrap();
+      let expected : Vec<Vec<String>> = vec![
+          vec!["list", "short", "sink-inputs"],
+          vec!["move-sink-input", "12", sink],
+          vec!["move-sink-input", "15", sink],
+      ].into_iter().map(|args| args.into_iter().map(String::from).collect()).collect();
+      assert_eq!(runner.calls(), expected);
+  }
+}
+
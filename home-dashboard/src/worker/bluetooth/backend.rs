@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use std::fs;
+use std::path::Path;
+use async_trait::async_trait;
+use bluez_async::{MacAddress, DeviceId, DeviceInfo, BluetoothEvent, DeviceEvent, AdapterEvent, BluetoothSession};
+use std::str::FromStr;
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::interface::AdapterState;
+use dbus::arg::PropMap;
+use dbus::message::SignalArgs;
+use dbus::blocking::{Connection, Proxy};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged, ObjectManagerInterfacesAdded};
+
+const PAIRING_TIMEOUT : Duration = Duration::from_secs(60);
+// how often battery signal thread checks that somebody still listens
+const BATTERY_SIGNAL_CHECK_INTERVAL : Duration = Duration::from_secs(1);
+const BATTERY_INTERFACE : &str = "org.bluez.Battery1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BtDeviceInfo {
+  pub mac : MacAddress,
+  pub name : Option<String>,
+  pub paired : bool,
+  pub connected : bool,
+  pub rssi : Option<i16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BtEvent {
+  /// device became known to the backend, i.e. it was paired or found by discovery
+  Discovered { mac : MacAddress },
+  Connected { mac : MacAddress, connected : bool },
+  Rssi { mac : MacAddress, rssi : i16 },
+  Discovering { discovering : bool },
+  Powered { powered : bool },
+  /// battery percentage is changed, or battery appeared after connection
+  Battery { mac : MacAddress, level : u8 },
+}
+
+/// Everything BluetoothModule needs from bluetooth stack. Devices are addressed by MAC,
+/// so implementations are free to map it to their own ids.
+#[async_trait]
+pub trait BluetoothBackend : Send + Sync {
+  async fn get_devices(&self) -> Result<Vec<BtDeviceInfo>, String>;
+  async fn get_device_info(&self, mac : &MacAddress) -> Result<BtDeviceInfo, String>;
+  async fn connect(&self, mac : &MacAddress) -> Result<(), String>;
+  async fn disconnect(&self, mac : &MacAddress) -> Result<(), String>;
+  async fn battery_level(&self, mac : &MacAddress) -> Option<u8>;
+  async fn start_discovery(&self) -> Result<(), String>;
+  async fn stop_discovery(&self) -> Result<(), String>;
+  async fn pair(&self, mac : &MacAddress) -> Result<(), String>;
+  /// trusted devices are allowed to connect without confirmation, i.e. when headset is powered on
+  async fn trust(&self, mac : &MacAddress) -> Result<(), String>;
+  async fn adapter_state(&self) -> AdapterState;
+  async fn set_powered(&self, powered : bool) -> Result<(), String>;
+  async fn device_event_stream(&self) -> Result<BoxStream<'static, BtEvent>, String>;
+  /// battery changes come as BtEvent::Battery, otherwise they are seen by polling battery_level only
+  fn has_battery_events(&self) -> bool;
+}
+
+pub struct BluezBackend {
+  session : BluetoothSession,
+  ids : Arc<Mutex<HashMap<MacAddress, DeviceId>>>,
+  // Battery1 signals are received, see battery_event_stream
+  battery_events : Arc<AtomicBool>,
+}
+
+impl BluezBackend {
+  pub async fn new() -> Result<Self, String> {
+      let session = BluetoothSession::new().await;
+      if let Err( e ) = session {
+            return Err( format!("Failed to open BluetoothSession : {:?} ; can't fulfill my duty.", e) );
+      }
+      let session = session.unwrap().1;
+
+      Ok( BluezBackend { session, ids : Arc::new(Mutex::new(HashMap::new())), battery_events : Arc::new(AtomicBool::new(false)) } )
+  }
+
+  async fn device_id(&self, mac : &MacAddress) -> Result<DeviceId, String> {
+      if let Some( id ) = self.ids.lock().unwrap().get(mac) {
+          return Ok( id.clone() );
+      }
+      // device could be paired after our last look at device list
+      self.get_devices().await?;
+      self.ids.lock().unwrap().get(mac).cloned().ok_or( format!("Failed to find device with mac {:?}", mac) )
+  }
+}
+
+#[async_trait]
+impl BluetoothBackend for BluezBackend {
+  async fn get_devices(&self) -> Result<Vec<BtDeviceInfo>, String> {
+      let devices = self.session.get_devices().await.map_err(|e| format!("Failed to get bluetooth device list : {:?}", e))?;
+      let mut ids = self.ids.lock().unwrap();
+      for device in &devices {
+          ids.insert(device.mac_address, device.id.clone());
+      }
+      Ok( devices.iter().map(from_device_info).collect() )
+  }
+
+  async fn get_device_info(&self, mac : &MacAddress) -> Result<BtDeviceInfo, String> {
+      let id = self.device_id(mac).await?;
+      let info = self.session.get_device_info(&id).await.map_err(|e| format!("Failed to get device info: {:?}", e))?;
+      Ok( from_device_info(&info) )
+  }
+
+  async fn connect(&self, mac : &MacAddress) -> Result<(), String> {
+      let id = self.device_id(mac).await?;
+      self.session.connect(&id).await.map_err(|e| e.to_string())
+  }
+
+  async fn disconnect(&self, mac : &MacAddress) -> Result<(), String> {
+      let id = self.device_id(mac).await?;
+      self.session.disconnect(&id).await.map_err(|e| e.to_string())
+  }
+
+  /// BlueZ exposes battery of headsets via org.bluez.Battery1 which is not covered by bluez_async
+  async fn battery_level(&self, mac : &MacAddress) -> Option<u8> {
+      let id = self.device_id(mac).await.ok()?;
+      let res = dbus_bluez_call(id.to_string(), Duration::from_secs(1), |proxy| proxy.get("org.bluez.Battery1", "Percentage")).await;
+
+      match res {
+        Err( e ) => {
+          log::debug!("Battery level is unavailable for {:?}: {}", id, e);
+          None
+        },
+        Ok( level ) => Some( level ),
+      }
+  }
+
+  async fn start_discovery(&self) -> Result<(), String> {
+      self.session.start_discovery().await.map_err(|e| e.to_string())
+  }
+
+  async fn stop_discovery(&self) -> Result<(), String> {
+      self.session.stop_discovery().await.map_err(|e| e.to_string())
+  }
+
+  async fn pair(&self, mac : &MacAddress) -> Result<(), String> {
+      let id = self.device_id(mac).await?;
+      dbus_bluez_call(id.to_string(), PAIRING_TIMEOUT, |proxy| proxy.method_call("org.bluez.Device1", "Pair", ())).await
+  }
+
+  async fn trust(&self, mac : &MacAddress) -> Result<(), String> {
+      let id = self.device_id(mac).await?;
+      dbus_bluez_call(id.to_string(), Duration::from_secs(1), |proxy| proxy.set("org.bluez.Device1", "Trusted", true)).await
+  }
+
+  async fn adapter_state(&self) -> AdapterState {
+      let adapters = match self.session.get_adapters().await {
+          Err( e ) => {
+              log::warn!("Failed to get bluetooth adapters: {:?}", e);
+              Vec::new()
+          },
+          Ok( a ) => a,
+      };
+
+      match adapters.first() {
+          None => AdapterState { blocked : is_rfkill_blocked(), ..AdapterState::default() },
+          Some( adapter ) => AdapterState {
+              present : true,
+              powered : adapter.powered,
+              discovering : adapter.discovering,
+              blocked : is_rfkill_blocked(),
+          },
+      }
+  }
+
+  async fn set_powered(&self, powered : bool) -> Result<(), String> {
+      if powered && is_rfkill_blocked() {
+          rfkill_soft_unblock()?;
+      }
+      let adapters = self.session.get_adapters().await.map_err(|e| format!("Failed to get bluetooth adapters: {:?}", e))?;
+      let adapter = adapters.first().ok_or( String::from("There is no bluetooth adapter") )?;
+      dbus_bluez_call(adapter.id.to_string(), Duration::from_secs(5), move |proxy| proxy.set("org.bluez.Adapter1", "Powered", powered)).await
+  }
+
+  async fn device_event_stream(&self) -> Result<BoxStream<'static, BtEvent>, String> {
+      let events = self.session.event_stream().await.map_err(|x| x.to_string())?;
+      let session = self.session.clone();
+      let ids = self.ids.clone();
+
+      let stream = events.filter_map(move |event| {
+          let session = session.clone();
+          let ids = ids.clone();
+          async move {
+              match event {
+                BluetoothEvent::Device { id, event : DeviceEvent::Connected{ connected } } => {
+                  let mac = resolve_mac(&session, &ids, id).await?;
+                  Some( BtEvent::Connected { mac, connected } )
+                },
+                BluetoothEvent::Device { id, event : DeviceEvent::Rssi{ rssi } } => {
+                  let mac = resolve_mac(&session, &ids, id).await?;
+                  Some( BtEvent::Rssi { mac, rssi } )
+                },
+                BluetoothEvent::Device { id, event : DeviceEvent::Discovered } => {
+                  let mac = resolve_mac(&session, &ids, id).await?;
+                  Some( BtEvent::Discovered { mac } )
+                },
+                BluetoothEvent::Adapter { event : AdapterEvent::Discovering{ discovering }, .. } => {
+                  Some( BtEvent::Discovering { discovering } )
+                },
+                BluetoothEvent::Adapter { event : AdapterEvent::Powered{ powered }, .. } => {
+                  Some( BtEvent::Powered { powered } )
+                },
+                _ => None,
+              }
+          }
+      });
+
+      // battery indicator is not worth losing connection tracking
+      match battery_event_stream(self.battery_events.clone()) {
+        Ok( batteries ) => Ok( stream::select(stream, batteries).boxed() ),
+        Err( e ) => {
+          log::warn!("Battery signals are unavailable, battery is updated by polling only: {}", e);
+          Ok( stream.boxed() )
+        },
+      }
+  }
+
+  fn has_battery_events(&self) -> bool {
+      self.battery_events.load(Ordering::Relaxed)
+  }
+}
+
+/// Battery1 is not covered by bluez_async, so its signals are received by own D-Bus connection.
+/// Dedicated thread is used because blocking connection has to be polled, active is cleared when it stops
+fn battery_event_stream(active : Arc<AtomicBool>) -> Result<BoxStream<'static, BtEvent>, String> {
+  let conn = Connection::new_system().map_err(|e| format!("Failed to connect to system bus: {}", e))?;
+  let (sender, receiver) = unbounded_channel();
+
+  let changed_sender = sender.clone();
+  let changed_rule = PropertiesPropertiesChanged::match_rule(None, None).static_clone();
+  conn.add_match(changed_rule, move |signal : PropertiesPropertiesChanged, _, msg| {
+      if signal.interface_name == BATTERY_INTERFACE {
+          send_battery_event(&changed_sender, msg.path().as_deref(), &signal.changed_properties);
+      }
+      true
+  }).map_err(|e| format!("Failed to subscribe to battery changes: {}", e))?;
+
+  let added_sender = sender.clone();
+  let added_rule = ObjectManagerInterfacesAdded::match_rule(None, None).static_clone();
+  conn.add_match(added_rule, move |signal : ObjectManagerInterfacesAdded, _, _| {
+      if let Some( props ) = signal.interfaces.get(BATTERY_INTERFACE) {
+          send_battery_event(&added_sender, Some( &*signal.object ), props);
+      }
+      true
+  }).map_err(|e| format!("Failed to subscribe to new batteries: {}", e))?;
+
+  active.store(true, Ordering::Relaxed);
+  std::thread::spawn(move || {
+      while !sender.is_closed() {
+          if let Err( e ) = conn.process(BATTERY_SIGNAL_CHECK_INTERVAL) {
+              log::warn!("Battery signal processing failed, battery is updated by polling only: {}", e);
+              break;
+          }
+      }
+      active.store(false, Ordering::Relaxed);
+  });
+
+  let stream = stream::unfold(receiver, |mut receiver| async move {
+      receiver.recv().await.map(|event| (event, receiver))
+  });
+  Ok( stream.boxed() )
+}
+
+fn send_battery_event(sender : &UnboundedSender<BtEvent>, path : Option<&str>, props : &PropMap) {
+  let mac = path.and_then(mac_from_device_path);
+  let level = props.get("Percentage").and_then(|v| v.0.as_u64());
+  if let (Some( mac ), Some( level )) = (mac, level) {
+      // error only means the stream is dropped, thread stops on its next check
+      let _ = sender.send( BtEvent::Battery { mac, level : level.min(100) as u8 } );
+  }
+}
+
+/// i.e. /org/bluez/hci0/dev_00_11_22_33_44_55
+fn mac_from_device_path(path : &str) -> Option<MacAddress> {
+  let dev = path.rsplit('/').next()?.strip_prefix("dev_")?;
+  MacAddress::from_str(&dev.replace('_', ":")).ok()
+}
+
+/// finds MAC of the device by id, asking BlueZ for devices we have not seen yet
+async fn resolve_mac(session : &BluetoothSession, ids : &Mutex<HashMap<MacAddress, DeviceId>>, id : DeviceId) -> Option<MacAddress> {
+  let mac = ids.lock().unwrap().iter().find(|(_, v)| **v == id).map(|(k, _)| *k);
+  if mac.is_some() {
+      return mac;
+  }
+
+  match session.get_device_info(&id).await {
+    Err( e ) => {
+      log::warn!("Failed to get info of device {:?}: {:?}", id, e);
+      None
+    },
+    Ok( info ) => {
+      ids.lock().unwrap().insert(info.mac_address, id);
+      Some( info.mac_address )
+    },
+  }
+}
+
+/// calls org.bluez object directly, for things bluez_async doesn't support.
+/// object is an adapter or device id, i.e. "hci0" or "hci0/dev_00_11_22_33_44_55"
+async fn dbus_bluez_call<R, F>(object : String, timeout : Duration, f : F) -> Result<R, String>
+where
+  R : Send + 'static,
+  F : FnOnce(Proxy<'_, &Connection>) -> Result<R, dbus::Error> + Send + 'static,
+{
+  let path = format!("/org/bluez/{}", object);
+  let res = tokio::task::spawn_blocking(move || -> Result<R, dbus::Error> {
+      let conn = Connection::new_system()?;
+      f( conn.with_proxy("org.bluez", path, timeout) )
+  }).await;
+
+  match res {
+    Err( e ) => Err( e.to_string() ),
+    Ok( res ) => res.map_err(|e| e.to_string()),
+  }
+}
+
+const RFKILL_PATH : &str = "/sys/class/rfkill";
+
+/// paths of rfkill switches of bluetooth type, i.e. /sys/class/rfkill/rfkill0
+fn bluetooth_rfkills() -> Vec<std::path::PathBuf> {
+  let Ok( entries ) = fs::read_dir(RFKILL_PATH) else { return Vec::new(); };
+  entries
+    .filter_map(|e| e.ok())
+    .map(|e| e.path())
+    .filter(|p| read_trimmed(&p.join("type")).as_deref() == Some( "bluetooth" ))
+    .collect()
+}
+
+fn is_rfkill_blocked() -> bool {
+  bluetooth_rfkills().iter().any(|p| {
+      read_trimmed(&p.join("soft")).as_deref() == Some( "1" ) || read_trimmed(&p.join("hard")).as_deref() == Some( "1" )
+  })
+}
+
+/// hard block is a physical switch, we can only try to lift soft one
+fn rfkill_soft_unblock() -> Result<(), String> {
+  for p in bluetooth_rfkills() {
+      fs::write(p.join("soft"), "0").map_err(|e| format!("Failed to unblock {}: {}", p.display(), e))?;
+  }
+  Ok(())
+}
+
+fn read_trimmed(path : &Path) -> Option<String> {
+  fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn from_device_info(info : &DeviceInfo) -> BtDeviceInfo {
+  BtDeviceInfo {
+    mac : info.mac_address,
+    name : info.name.clone(),
+    paired : info.paired,
+    connected : info.connected,
+    rssi : info.rssi,
+  }
+}
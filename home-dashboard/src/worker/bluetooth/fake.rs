@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use bluez_async::MacAddress;
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::interface::AdapterState;
+use super::backend::{BluetoothBackend, BtDeviceInfo, BtEvent};
+
+/// In-memory bluetooth stack. Devices and events are scripted by hand,
+/// so GUI and worker could be run on a machine without BlueZ.
+pub struct FakeBluetoothBackend {
+  state : Mutex<FakeState>,
+  events : broadcast::Sender<BtEvent>,
+}
+
+#[derive(Default)]
+struct FakeState {
+  devices : HashMap<MacAddress, BtDeviceInfo>,
+  batteries : HashMap<MacAddress, u8>,
+  // powered off or out of range, connect fails
+  unreachable : HashSet<MacAddress>,
+  adapter : AdapterState,
+}
+
+impl FakeBluetoothBackend {
+  pub fn new() -> Self {
+      const MAX_NUM_EVENTS : usize = 64;
+      let (events, _) = broadcast::channel(MAX_NUM_EVENTS);
+      let state = FakeState {
+          adapter : AdapterState { present : true, powered : true, ..AdapterState::default() },
+          ..FakeState::default()
+      };
+      FakeBluetoothBackend { state : Mutex::new(state), events }
+  }
+
+  /// device appears as if it was just paired
+  pub fn add_device(&self, mac : MacAddress, name : &str) {
+      self.insert_device( BtDeviceInfo { mac, name : Some( name.to_string() ), paired : true, connected : false, rssi : None } );
+  }
+
+  /// device appears as if it was found by discovery, it's not paired yet
+  pub fn add_nearby_device(&self, mac : MacAddress, name : &str, rssi : i16) {
+      self.insert_device( BtDeviceInfo { mac, name : Some( name.to_string() ), paired : false, connected : false, rssi : Some( rssi ) } );
+  }
+
+  fn insert_device(&self, info : BtDeviceInfo) {
+      let mac = info.mac;
+      self.state.lock().unwrap().devices.insert(mac, info);
+      self.emit( BtEvent::Discovered { mac } );
+  }
+
+  /// changes connection state as if it was done outside of dashboard, i.e. device is powered off
+  pub fn set_connected(&self, mac : &MacAddress, connected : bool) {
+      if let Some( device ) = self.state.lock().unwrap().devices.get_mut(mac) {
+          device.connected = connected;
+      }
+      self.emit( BtEvent::Connected { mac : *mac, connected } );
+  }
+
+  pub fn set_battery(&self, mac : &MacAddress, level : Option<u8>) {
+      let mut state = self.state.lock().unwrap();
+      match level {
+          Some( level ) => state.batteries.insert(*mac, level),
+          None => state.batteries.remove(mac),
+      };
+      drop(state);
+      if let Some( level ) = level {
+          self.emit( BtEvent::Battery { mac : *mac, level } );
+      }
+  }
+
+  #[cfg(test)]
+  pub fn set_reachable(&self, mac : &MacAddress, reachable : bool) {
+      let mut state = self.state.lock().unwrap();
+      if reachable {
+          state.unreachable.remove(mac);
+      } else {
+          state.unreachable.insert(*mac);
+      }
+  }
+
+  fn emit(&self, event : BtEvent) {
+      // error only means nobody is subscribed yet
+      let _ = self.events.send(event);
+  }
+
+  fn set_connected_checked(&self, mac : &MacAddress, connected : bool) -> Result<(), String> {
+      let state = self.state.lock().unwrap();
+      match state.devices.get(mac) {
+          None => return Err( format!("Failed to find device with mac {:?}", mac) ),
+          Some( device ) if connected && !device.paired => return Err( String::from("Device is not paired") ),
+          Some( _ ) if connected && state.unreachable.contains(mac) => return Err( String::from("Page Timeout") ),
+          Some( _ ) => (),
+      }
+      drop(state);
+      self.set_connected(mac, connected);
+      Ok(())
+  }
+}
+
+#[async_trait]
+impl BluetoothBackend for FakeBluetoothBackend {
+  async fn get_devices(&self) -> Result<Vec<BtDeviceInfo>, String> {
+      Ok( self.state.lock().unwrap().devices.values().cloned().collect() )
+  }
+
+  async fn get_device_info(&self, mac : &MacAddress) -> Result<BtDeviceInfo, String> {
+      self.state.lock().unwrap().devices.get(mac).cloned().ok_or( format!("Failed to find device with mac {:?}", mac) )
+  }
+
+  async fn connect(&self, mac : &MacAddress) -> Result<(), String> {
+      self.set_connected_checked(mac, true)
+  }
+
+  async fn disconnect(&self, mac : &MacAddress) -> Result<(), String> {
+      self.set_connected_checked(mac, false)
+  }
+
+  async fn battery_level(&self, mac : &MacAddress) -> Option<u8> {
+      self.state.lock().unwrap().batteries.get(mac).copied()
+  }
+
+  async fn start_discovery(&self) -> Result<(), String> {
+      self.state.lock().unwrap().adapter.discovering = true;
+      self.emit( BtEvent::Discovering { discovering : true } );
+      // there is always somebody nearby
+      let mac = MacAddress::from_str("00:11:22:33:44:55").unwrap();
+      self.add_nearby_device(mac, "Fake Speaker", -60);
+      Ok(())
+  }
+
+  async fn stop_discovery(&self) -> Result<(), String> {
+      self.state.lock().unwrap().adapter.discovering = false;
+      self.emit( BtEvent::Discovering { discovering : false } );
+      Ok(())
+  }
+
+  async fn pair(&self, mac : &MacAddress) -> Result<(), String> {
+      match self.state.lock().unwrap().devices.get_mut(mac) {
+          None => Err( format!("Failed to find device with mac {:?}", mac) ),
+          Some( device ) => {
+              device.paired = true;
+              Ok(())
+          },
+      }
+  }
+
+  async fn trust(&self, mac : &MacAddress) -> Result<(), String> {
+      self.get_device_info(mac).await.map(|_| ())
+  }
+
+  async fn adapter_state(&self) -> AdapterState {
+      self.state.lock().unwrap().adapter.clone()
+  }
+
+  async fn set_powered(&self, powered : bool) -> Result<(), String> {
+      let connected : Vec<MacAddress> = {
+          let mut state = self.state.lock().unwrap();
+          state.adapter.powered = powered;
+          state.devices.values().filter(|d| d.connected).map(|d| d.mac).collect()
+      };
+      self.emit( BtEvent::Powered { powered } );
+      if !powered {
+          for mac in connected {
+              self.set_connected(&mac, false);
+          }
+      }
+      Ok(())
+  }
+
+  async fn device_event_stream(&self) -> Result<BoxStream<'static, BtEvent>, String> {
+      let receiver = self.events.subscribe();
+      let stream = stream::unfold(receiver, |mut receiver| async move {
+          loop {
+              match receiver.recv().await {
+                  Ok( event ) => return Some( (event, receiver) ),
+                  Err( RecvError::Lagged( n ) ) => log::warn!("Fake BT event stream lagged by {} events", n),
+                  Err( RecvError::Closed ) => return None,
+              }
+          }
+      });
+      Ok( stream.boxed() )
+  }
+
+  /// set_battery emits events
+  fn has_battery_events(&self) -> bool {
+      true
+  }
+}
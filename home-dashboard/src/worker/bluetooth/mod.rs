@@ -0,0 +1,979 @@
+use log;
+use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{Local, Timelike};
+use bluez_async::MacAddress;
+use futures::stream::{BoxStream, StreamExt};
+use crate::interface::*;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::Notify;
+
+mod backend;
+mod fake;
+mod audio;
+use backend::*;
+use fake::FakeBluetoothBackend;
+use audio::*;
+
+// battery polling when backend has no battery events
+const BATTERY_POLL_INTERVAL : Duration = Duration::from_secs(60);
+// with battery events polling only catches missed ones
+const BATTERY_EVENTS_POLL_INTERVAL : Duration = Duration::from_secs(300);
+const AUDIO_POLL_INTERVAL : Duration = Duration::from_secs(5);
+const RESYNC_INTERVAL : Duration = Duration::from_secs(300);
+// missing devices are looked for on Discovered events no more often than this, discovery brings lots of other devices
+const RESOLVE_INTERVAL : Duration = Duration::from_secs(300);
+
+const RECONNECT_CHECK_INTERVAL : Duration = Duration::from_secs(5);
+const RECONNECT_MIN_BACKOFF : Duration = Duration::from_secs(5);
+const RECONNECT_MAX_BACKOFF : Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+pub struct BluetoothModule {
+  backend : Arc<dyn BluetoothBackend>,
+  // grows when device is paired from dashboard
+  devices : Arc<Mutex<Vec<BluetoothDevice>>>,
+  exclusive_groups : Vec<String>,
+  audio_config : AudioConfig,
+  audio : AudioSinks,
+  // devices which are known to backend, devices not paired yet are missing here
+  available : Arc<Mutex<HashSet<DeviceKey>>>,
+  // last look for devices which are not known to backend
+  resolved_at : Arc<Mutex<Option<Instant>>>,
+  reconnects : Arc<Mutex<HashMap<DeviceKey, ReconnectTracker>>>,
+  // disconnected by user, so we don't fight with them; kept until Connect, whatever connection state is
+  suppressed : Arc<Mutex<HashSet<DeviceKey>>>,
+  // device list, pairing or adapter is changed by command, watch loop should rebuild its state
+  devices_changed : Arc<Notify>,
+}
+
+#[derive(Default)]
+struct ReconnectTracker {
+  attempt : u32,
+  last_error : Option<String>,
+  next_attempt : Option<Instant>,
+  // connect is running in its own task, BlueZ page timeout takes many seconds
+  in_progress : bool,
+}
+
+#[derive(Clone)]
+struct BluetoothDevice {
+  key : DeviceKey,
+  cfg : BluetoothDeviceConfig,
+  // None if MAC in configuration is malformed
+  mac : Option<MacAddress>,
+}
+
+impl BluetoothModule {
+  pub async fn new(bt_config : &BluetoothConfig) -> Result<Self, String> {
+      let (backend, runner) : (Arc<dyn BluetoothBackend>, Arc<dyn CommandRunner>) = match bt_config.backend {
+          BluetoothBackendKind::Bluez => ( Arc::new( BluezBackend::new().await? ), Arc::new( SystemCommandRunner ) ),
+          BluetoothBackendKind::Fake => ( Arc::new( fake_backend(bt_config) ), Arc::new( fake_command_runner(bt_config) ) ),
+      };
+      Ok( BluetoothModule::with_backend(bt_config, backend, runner).await )
+  }
+
+  pub async fn with_backend(bt_config : &BluetoothConfig, backend : Arc<dyn BluetoothBackend>, runner : Arc<dyn CommandRunner>) -> Self {
+      let devices : Vec<BluetoothDevice> = bt_config.devices.iter().map(BluetoothDevice::new).collect();
+
+      let bt_module = BluetoothModule {
+          backend,
+          devices : Arc::new(Mutex::new(devices)),
+          exclusive_groups : bt_config.exclusive_groups.clone(),
+          audio_config : bt_config.audio.clone(),
+          audio : AudioSinks::new(runner),
+          available : Arc::new(Mutex::new(HashSet::new())),
+          resolved_at : Arc::new(Mutex::new(None)),
+          reconnects : Arc::new(Mutex::new(HashMap::new())),
+          suppressed : Arc::new(Mutex::new(HashSet::new())),
+          devices_changed : Arc::new(Notify::new()),
+      };
+      bt_module.resolve_devices().await;
+
+      bt_module
+  }
+
+  /// looks for configured devices which are not yet paired within backend device list
+  pub async fn resolve_devices(&self) {
+      let known_devices = match self.backend.get_devices().await {
+          Err( e ) => {
+              log::warn!("{}", e);
+              return;
+          },
+          Ok( d ) => d,
+      };
+      *self.resolved_at.lock().unwrap() = Some( Instant::now() );
+      let paired_devices : Vec<BtDeviceInfo> = known_devices.into_iter().filter(|d| d.paired).collect();
+
+      for device in self.devices() {
+          // malformed MAC is reported once at startup, see BluetoothDevice::new
+          let Some( mac ) = &device.mac else { continue; };
+          if self.device_mac(&device.key).is_some() {
+              continue;
+          }
+          match find_device_id(&paired_devices, mac) {
+              Err( e ) => log::warn!("{} is unavailable: {}", device.cfg.name, e),
+              Ok( mac ) => {
+                  log::info!("{} is found as {:?}", device.cfg.name, mac);
+                  self.available.lock().unwrap().insert(device.key.clone());
+              },
+          }
+      }
+  }
+
+  pub async fn get_state(&self) -> BluetoothState {
+      let mut bt_state = BluetoothState { adapter : self.backend.adapter_state().await, ..BluetoothState::default() };
+      for device in self.devices() {
+          let mac = self.device_mac(&device.key);
+          let is_connected = match &mac {
+              Some( mac ) => check_bluetooth_status(mac, self.backend.as_ref()).await,
+              None => false,
+          };
+          let battery = match &mac {
+              Some( mac ) if is_connected => self.backend.battery_level(mac).await,
+              _ => None,
+          };
+          bt_state.devices.push( BluetoothDeviceState {
+              key : device.key.clone(),
+              name : device.cfg.name.clone(),
+              icon : device.cfg.icon.clone(),
+              group : device.cfg.group.clone(),
+              is_exclusive : self.is_exclusive(&device.cfg),
+              is_available : mac.is_some(),
+              is_connected,
+              is_audio_output : false,
+              battery,
+              reconnect : None,
+          });
+      }
+      bt_state
+  }
+
+  pub async fn event_stream(&self) -> Result<BoxStream<'static, BtEvent>, String> {
+      self.backend.device_event_stream().await
+  }
+
+  /// MAC of the device if it's available
+  fn device_mac(&self, key : &DeviceKey) -> Option<MacAddress> {
+      if !self.available.lock().unwrap().contains(key) {
+          return None;
+      }
+      self.devices.lock().unwrap().iter().find(|d| &d.key == key).and_then(|d| d.mac)
+  }
+
+  fn devices(&self) -> Vec<BluetoothDevice> {
+      self.devices.lock().unwrap().clone()
+  }
+
+  /// rebuilds device list after it or availability of devices changed,
+  /// keeping what is tracked by the watch loop only
+  async fn refresh_devices(&self, bt_state : &mut BluetoothState) {
+      let mut devices = self.get_state().await.devices;
+      for device in devices.iter_mut() {
+          if let Some( old ) = bt_state.devices.iter().find(|d| d.key == device.key) {
+              device.is_audio_output = old.is_audio_output;
+              device.reconnect = old.reconnect.clone();
+          }
+      }
+      bt_state.devices = devices;
+  }
+
+  /// re-reads adapter and connection status of devices in case some events were missed
+  async fn resync_state(&self, bt_state : &mut BluetoothState) {
+      // device paired outside of dashboard, i.e. by bluetoothctl, may produce no event at all
+      if self.has_unresolved_devices() {
+          self.resolve_devices().await;
+          self.refresh_devices(bt_state).await;
+      }
+      let adapter = self.backend.adapter_state().await;
+      if adapter != bt_state.adapter {
+          log::info!("BT adapter state is changed: {:?}", adapter);
+          bt_state.adapter = adapter;
+      }
+      for device in bt_state.devices.iter_mut() {
+          let Some( mac ) = self.device_mac(&device.key) else { continue; };
+          let is_connected = check_bluetooth_status(&mac, self.backend.as_ref()).await;
+          if is_connected != device.is_connected {
+              log::warn!("{} connection state drifted, fixing it to {}", device.name, is_connected);
+              device.is_connected = is_connected;
+          }
+      }
+  }
+
+  /// re-reads battery level of all connected devices
+  async fn update_battery(&self, bt_state : &mut BluetoothState) {
+      for device in bt_state.devices.iter_mut() {
+          device.battery = match self.device_mac(&device.key) {
+              Some( mac ) if device.is_connected => self.backend.battery_level(&mac).await,
+              _ => None,
+          };
+      }
+  }
+
+  /// starts connecting devices which should be connected according to their ReconnectPolicy,
+  /// attempts run in background and their outcome is shown on the next call
+  fn reconnect_devices(&self, bt_state : &mut BluetoothState) {
+      let now = Instant::now();
+      for device in self.devices() {
+          let Some( device_state ) = bt_state.devices.iter_mut().find(|d| d.key == device.key) else { continue; };
+          let Some( mac ) = self.device_mac(&device.key) else { continue; };
+
+          let suppressed = self.suppressed.lock().unwrap().contains(&device.key);
+          if device_state.is_connected || suppressed || !is_policy_active(&device.cfg.reconnect, Local::now().hour()) {
+              let mut reconnects = self.reconnects.lock().unwrap();
+              if !reconnects.get(&device.key).is_some_and(|t| t.in_progress) {
+                  reconnects.remove(&device.key);
+              }
+              device_state.reconnect = None;
+              continue;
+          }
+
+          let mut reconnects = self.reconnects.lock().unwrap();
+          let tracker = reconnects.entry(device.key.clone()).or_default();
+          if tracker.attempt > 0 {
+              device_state.reconnect = Some( ReconnectState { attempt : tracker.attempt, last_error : tracker.last_error.clone() } );
+          }
+          let is_due = match tracker.next_attempt {
+              None => true,
+              Some( t ) => t <= now,
+          };
+          if tracker.in_progress || !is_due {
+              continue;
+          }
+          tracker.in_progress = true;
+
+          log::debug!("Trying to reconnect {}", device.cfg.name);
+          let bt_module = self.clone();
+          let device = device.clone();
+          tokio::spawn( async move {
+              let res = bt_module.backend.connect(&mac).await;
+              let mut reconnects = bt_module.reconnects.lock().unwrap();
+              let tracker = reconnects.entry(device.key.clone()).or_default();
+              tracker.in_progress = false;
+              match res {
+                  Ok( () ) => {
+                      log::info!("{} is reconnected", device.cfg.name);
+                      *tracker = ReconnectTracker::default();
+                  },
+                  Err( e ) => {
+                      tracker.attempt += 1;
+                      log::debug!("Reconnect attempt {} for {} failed: {:?}", tracker.attempt, device.cfg.name, e);
+                      tracker.last_error = Some( e );
+                      tracker.next_attempt = Some( Instant::now() + reconnect_backoff(tracker.attempt) );
+                  },
+              }
+          });
+      }
+  }
+
+  /// adds or refreshes device in the list of discovered ones, unless it's configured already
+  async fn update_discovered(&self, bt_state : &mut BluetoothState, mac : &MacAddress, rssi : Option<i16>) {
+      if self.find_key(mac).is_some() {
+          return;
+      }
+      let mac_string = mac.to_string();
+      if let Some( device ) = bt_state.discovered.iter_mut().find(|d| d.mac == mac_string) {
+          if rssi.is_some() {
+              device.rssi = rssi;
+          }
+          return;
+      }
+      match self.backend.get_device_info(mac).await {
+          Err( e ) => log::warn!("{}", e),
+          Ok( info ) if info.paired => (),
+          Ok( info ) => bt_state.discovered.push( DiscoveredDevice { mac : mac_string, name : info.name, rssi : rssi.or(info.rssi) } ),
+      }
+  }
+
+  /// re-reads default sink and marks device it belongs to
+  async fn update_audio_output(&self, bt_state : &mut BluetoothState) {
+      let default_sink = match self.audio.default_sink().await {
+          Err( e ) => {
+              log::debug!("Failed to get default sink: {}", e);
+              None
+          },
+          Ok( sink ) => Some( sink ),
+      };
+
+      for device in bt_state.devices.iter_mut() {
+          let mac = self.devices.lock().unwrap().iter().find(|d| d.key == device.key).and_then(|d| d.mac);
+          device.is_audio_output = match (&default_sink, mac) {
+              (Some( sink ), Some( mac )) => AudioSinks::is_sink_of(sink, &mac),
+              _ => false,
+          };
+      }
+      bt_state.default_sink = default_sink;
+  }
+
+  /// makes sink of just connected device the default one
+  async fn switch_audio_output(&self, mac : &MacAddress) -> Result<(), String> {
+      let sink = self.audio.wait_bluetooth_sink(mac).await?;
+      log::info!("Setting default sink to {}", sink);
+      self.audio.set_default_sink(&sink).await?;
+      if self.audio_config.move_streams {
+          self.audio.move_streams(&sink).await?;
+      }
+      Ok(())
+  }
+
+  fn suppress_reconnect(&self, key : &DeviceKey, suppressed : bool) {
+      if suppressed {
+          self.suppressed.lock().unwrap().insert(key.clone());
+      } else {
+          self.suppressed.lock().unwrap().remove(key);
+      }
+      if let Some( tracker ) = self.reconnects.lock().unwrap().get_mut(key) {
+          tracker.attempt = 0;
+          tracker.last_error = None;
+          tracker.next_attempt = None;
+      }
+  }
+
+  fn find_key(&self, mac : &MacAddress) -> Option<DeviceKey> {
+      self.devices.lock().unwrap().iter().find(|d| d.mac.as_ref() == Some( mac )).map(|d| d.key.clone())
+  }
+
+  fn is_exclusive(&self, cfg : &BluetoothDeviceConfig) -> bool {
+      cfg.group.as_ref().is_some_and(|g| self.exclusive_groups.contains(g))
+  }
+
+  /// disconnects other connected devices of the same exclusive group, they are not reconnected until Connect command
+  async fn disconnect_group_peers(&self, key : &DeviceKey) {
+      let devices = self.devices();
+      let Some( device ) = devices.iter().find(|d| &d.key == key) else { return; };
+      if !self.is_exclusive(&device.cfg) {
+          return;
+      }
+
+      for peer in devices.iter().filter(|d| &d.key != key && d.cfg.group == device.cfg.group) {
+          let Some( mac ) = self.device_mac(&peer.key) else { continue; };
+          if !check_bluetooth_status(&mac, self.backend.as_ref()).await {
+              continue;
+          }
+          log::info!("Disconnecting {} because {} is requested", peer.cfg.name, device.cfg.name);
+          // peer is disconnected on purpose, it should not be reconnected back
+          self.suppress_reconnect(&peer.key, true);
+          if let Err( e ) = self.backend.disconnect(&mac).await {
+              log::warn!("Error while disconnecting to {:?} : {}", mac, e);
+          }
+      }
+  }
+
+  /// devices with malformed MAC are never resolved, so they don't count
+  fn has_unresolved_devices(&self) -> bool {
+      let available = self.available.lock().unwrap();
+      self.devices.lock().unwrap().iter().any(|d| d.mac.is_some() && !available.contains(&d.key))
+  }
+
+  fn is_resolve_due(&self) -> bool {
+      match *self.resolved_at.lock().unwrap() {
+          None => true,
+          Some( t ) => t.elapsed() >= RESOLVE_INTERVAL,
+      }
+  }
+
+  /// pairs discovered device and adds it to configured devices
+  async fn pair(&self, mac_string : &str) -> Result<(), String> {
+      let mac = MacAddress::from_str(mac_string).map_err(|e| e.to_string())?;
+      if self.find_key(&mac).is_some() {
+          return Err( format!("{} is already configured", mac_string) );
+      }
+
+      self.backend.pair(&mac).await?;
+      self.backend.trust(&mac).await?;
+      let info = self.backend.get_device_info(&mac).await?;
+
+      // name is the key of device, so it's made unique, i.e. for the second headset of the same model
+      let names : Vec<String> = self.devices().iter().map(|d| d.cfg.name.clone()).collect();
+      let cfg = BluetoothDeviceConfig {
+          name : unique_device_name(&names, info.name.unwrap_or( mac_string.to_string() ), &mac),
+          mac : mac_string.to_string(),
+          ..BluetoothDeviceConfig::default()
+      };
+      log::info!("{} is paired, adding it to configuration", cfg.name);
+      store_device_config(&cfg)?;
+
+      self.devices.lock().unwrap().push( BluetoothDevice::new(&cfg) );
+      self.resolve_devices().await;
+      self.devices_changed.notify_one();
+      Ok(())
+  }
+}
+
+impl BluetoothDevice {
+  fn new(cfg : &BluetoothDeviceConfig) -> Self {
+      let mac = MacAddress::from_str(&cfg.mac).ok();
+      if mac.is_none() {
+          log::error!("bad MAC {} in configuration of {}. Can't parse it.", cfg.mac, cfg.name);
+      }
+      BluetoothDevice { key : cfg.key(), cfg : cfg.clone(), mac }
+  }
+}
+
+pub async fn execute_command(bt_module : &BluetoothModule, cmd : HomeCommand)
+{
+  log::debug!("Got CMD: {:?}", cmd);
+  match cmd {
+    HomeCommand::Connect( key ) => {
+      bt_module.suppress_reconnect(&key, false);
+      let Some( mac ) = bt_module.device_mac(&key) else {
+        log::warn!("Can't connect to unavailable device {:?}", key);
+        return;
+      };
+      if let Err( e ) =  bt_module.backend.connect(&mac).await {
+        log::warn!("Error while connecting to {:?} : {}", mac, e);
+        return;
+      }
+      // only after successful connect, so failed one doesn't leave the group without any device
+      bt_module.disconnect_group_peers(&key).await;
+      if bt_module.audio_config.set_default_sink {
+        // waiting for sink takes a while, don't block other commands
+        let bt_module = bt_module.clone();
+        tokio::spawn( async move {
+          if let Err( e ) = bt_module.switch_audio_output(&mac).await {
+            log::warn!("Failed to switch audio output to {:?} : {}", mac, e);
+          }
+        });
+      }
+    },
+    HomeCommand::Disconnect( key ) => {
+      bt_module.suppress_reconnect(&key, true);
+      let Some( mac ) = bt_module.device_mac(&key) else {
+        log::warn!("Can't disconnect from unavailable device {:?}", key);
+        return;
+      };
+      if let Err( e ) =  bt_module.backend.disconnect(&mac).await {
+        log::warn!("Error while disconnecting to {:?} : {}", mac, e);
+      }
+    },
+    HomeCommand::StartDiscovery => {
+      if let Err( e ) = bt_module.backend.start_discovery().await {
+        log::warn!("Failed to start discovery: {}", e);
+      }
+    },
+    HomeCommand::StopDiscovery => {
+      if let Err( e ) = bt_module.backend.stop_discovery().await {
+        log::warn!("Failed to stop discovery: {}", e);
+      }
+    },
+    HomeCommand::Pair( mac ) => {
+      // pairing waits for confirmation on device up to a minute, don't block other commands
+      let bt_module = bt_module.clone();
+      tokio::spawn( async move {
+        if let Err( e ) = bt_module.pair(&mac).await {
+          log::warn!("Failed to pair {} : {}", mac, e);
+        }
+      });
+    },
+    HomeCommand::SetAdapterPowered( powered ) => {
+      if let Err( e ) = bt_module.backend.set_powered(powered).await {
+        log::warn!("Failed to power adapter {} : {}", if powered { "on" } else { "off" }, e);
+      }
+      // rfkill state is not reported by events
+      bt_module.devices_changed.notify_one();
+    },
+    cmd => log::warn!("{:?} is not a bluetooth command", cmd),
+  };
+}
+
+pub async fn watch_bluetooth_loop(
+    bt_module : BluetoothModule ,
+    bt_sender : Sender<BluetoothState>) -> Result<(), String>
+{
+  // subscribe first, so events which happen during initial state request are queued in the stream
+  // and applied on top of the state afterwards
+  let mut event_stream = bt_module.event_stream().await?;
+  let mut bt_state = bt_module.get_state().await;
+  let mut resync_interval = tokio::time::interval(RESYNC_INTERVAL);
+  resync_interval.reset();
+  let mut battery_interval = tokio::time::interval(BATTERY_POLL_INTERVAL);
+  let mut battery_polled_at = Instant::now();
+  let mut reconnect_interval = tokio::time::interval(RECONNECT_CHECK_INTERVAL);
+  let mut audio_interval = tokio::time::interval(AUDIO_POLL_INTERVAL);
+
+  loop {
+    match bt_sender.try_send(bt_state.clone()) {
+      Ok(()) => (),
+      Err( TrySendError::Full( _ ) ) => log::warn!("Failed to send BT data, update_state_loop is not consuming it!"),
+      Err( TrySendError::Closed( _ ) ) => {
+        log::warn!("Failed to send data - channel is closed. Probably update_state_loop is dead now. Exiting....");
+        return Ok(());
+      },
+    }
+
+    tokio::select! {
+      event = event_stream.next() => match event {
+        Some( BtEvent::Connected { mac, connected } ) => {
+          log::debug!("Got BT connected event {:?} {}", mac, connected);
+          if let Some( key ) = bt_module.find_key(&mac) {
+            if let Some( device ) = bt_state.devices.iter_mut().find(|d| d.key == key) {
+              device.is_connected = connected;
+              // Battery1 interface usually appears a bit later than connection, Battery event brings it then
+              device.battery = if connected { bt_module.backend.battery_level(&mac).await } else { None };
+            }
+          }
+        }
+        Some( BtEvent::Discovered { mac } ) => {
+          // missing device may never appear, while discovery brings lots of others
+          if bt_module.has_unresolved_devices() && bt_module.is_resolve_due() {
+            log::debug!("New BT device {:?} appeared, trying to resolve unavailable devices", mac);
+            bt_module.resolve_devices().await;
+            bt_module.refresh_devices(&mut bt_state).await;
+          }
+          if bt_state.adapter.discovering {
+            bt_module.update_discovered(&mut bt_state, &mac, None).await;
+          }
+        }
+        Some( BtEvent::Rssi { mac, rssi } ) => {
+          if bt_state.adapter.discovering {
+            bt_module.update_discovered(&mut bt_state, &mac, Some( rssi )).await;
+          }
+        }
+        Some( BtEvent::Battery { mac, level } ) => {
+          log::debug!("Got BT battery event {:?} {}", mac, level);
+          if let Some( key ) = bt_module.find_key(&mac) {
+            if let Some( device ) = bt_state.devices.iter_mut().find(|d| d.key == key && d.is_connected) {
+              device.battery = Some( level );
+            }
+          }
+        }
+        Some( BtEvent::Discovering { discovering } ) => {
+          log::debug!("BT discovering {}", discovering);
+          if discovering && !bt_state.adapter.discovering {
+            bt_state.discovered.clear();
+          }
+          bt_state.adapter.discovering = discovering;
+        }
+        Some( BtEvent::Powered { powered } ) => {
+          log::info!("BT adapter powered {}", powered);
+          bt_state.adapter.powered = powered;
+        }
+        None => { break; }
+      },
+      _ = bt_module.devices_changed.notified() => {
+        bt_state.adapter = bt_module.backend.adapter_state().await;
+        bt_module.refresh_devices(&mut bt_state).await;
+        bt_state.discovered.retain(|d| MacAddress::from_str(&d.mac).map_or(true, |mac| bt_module.find_key(&mac).is_none()));
+      }
+      _ = audio_interval.tick() => {
+        bt_module.update_audio_output(&mut bt_state).await;
+      }
+      _ = battery_interval.tick() => {
+        if !bt_module.backend.has_battery_events() || battery_polled_at.elapsed() >= BATTERY_EVENTS_POLL_INTERVAL {
+          bt_module.update_battery(&mut bt_state).await;
+          battery_polled_at = Instant::now();
+        }
+      }
+      _ = resync_interval.tick() => {
+        bt_module.resync_state(&mut bt_state).await;
+      }
+      _ = reconnect_interval.tick(), if bt_state.adapter.powered => {
+        bt_module.reconnect_devices(&mut bt_state);
+      }
+    }
+
+  }
+
+  log::warn!("Event stream from BT is ended... strange... exiting...");
+  Ok(())
+}
+
+
+pub fn unavailable_bt_state(bt_config : &BluetoothConfig) -> BluetoothState {
+  let mut bt_state = BluetoothState::default();
+  for cfg in &bt_config.devices {
+      bt_state.devices.push( BluetoothDeviceState {
+          key : cfg.key(),
+          name : cfg.name.clone(),
+          icon : cfg.icon.clone(),
+          group : cfg.group.clone(),
+          is_exclusive : bt_config.is_exclusive(&cfg.group),
+          is_available : false,
+          is_connected : false,
+          is_audio_output : false,
+          battery : None,
+          reconnect : None,
+      });
+  }
+  bt_state
+}
+
+async fn check_bluetooth_status(mac : &MacAddress, backend : &dyn BluetoothBackend) -> bool {
+
+  match backend.get_device_info(mac).await {
+    Err( e ) => {
+       log::warn!("{}", e);
+       false
+    },
+    Ok( info ) => info.connected,
+  }
+
+}
+
+/// hour is local one, 0..24
+fn is_policy_active(policy : &ReconnectPolicy, hour : u32) -> bool {
+  match policy {
+    ReconnectPolicy::Manual => false,
+    ReconnectPolicy::KeepConnected => true,
+    // overnight range, i.e. from 22 to 6
+    ReconnectPolicy::WorkingHours { from_hour, to_hour } if from_hour > to_hour => *from_hour <= hour || hour < *to_hour,
+    ReconnectPolicy::WorkingHours { from_hour, to_hour } => *from_hour <= hour && hour < *to_hour,
+  }
+}
+
+fn reconnect_backoff(attempt : u32) -> Duration {
+  let backoff = RECONNECT_MIN_BACKOFF.saturating_mul( 2u32.saturating_pow( attempt.saturating_sub(1) ) );
+  backoff.min(RECONNECT_MAX_BACKOFF)
+}
+
+/// name, or name with the end of MAC if it's taken, i.e. "Headset (EE:01)"
+fn unique_device_name(names : &[String], name : String, mac : &MacAddress) -> String {
+  if !names.contains(&name) {
+      return name;
+  }
+  let mac_string = mac.to_string();
+  let short = format!("{} ({})", name, &mac_string[mac_string.len() - 5..]);
+  if !names.contains(&short) {
+      return short;
+  }
+  format!("{} ({})", name, mac_string)
+}
+
+/// appends device to configuration file, so it survives restart
+fn store_device_config(device_cfg : &BluetoothDeviceConfig) -> Result<(), String> {
+  let mut cfg : HomeDashboardConfig = confy::load(CONFIGURATION_NAME, None).map_err(|e| format!("Failed to load configuration: {:?}", e))?;
+  cfg.bt_config.devices.push( device_cfg.clone() );
+  confy::store(CONFIGURATION_NAME, None, cfg).map_err(|e| format!("Failed to store configuration: {:?}", e))
+}
+
+/// fake backend pre-populated with configured devices, for running dashboard without BlueZ
+fn fake_backend(bt_config : &BluetoothConfig) -> FakeBluetoothBackend {
+  let backend = FakeBluetoothBackend::new();
+  for cfg in &bt_config.devices {
+      if let Ok( mac ) = MacAddress::from_str(&cfg.mac) {
+          backend.add_device(mac, &cfg.name);
+          backend.set_battery(&mac, Some( 80 ));
+      }
+  }
+  backend
+}
+
+/// fake pactl which knows sinks of configured devices
+fn fake_command_runner(bt_config : &BluetoothConfig) -> FakeCommandRunner {
+  let runner = FakeCommandRunner::default();
+  for cfg in &bt_config.devices {
+      if let Ok( mac ) = MacAddress::from_str(&cfg.mac) {
+          runner.add_bluetooth_sink(&mac);
+      }
+  }
+  runner
+}
+
+pub fn find_device_id(devices : &[BtDeviceInfo], mac : &MacAddress) -> Result<MacAddress, String> {
+  let device = devices.iter().find(|device| device.mac == *mac);
+  if device.is_none() {
+      return Err( format!("Failed to find device with mac {:?}", mac) );
+  };
+
+  Ok( device.unwrap().mac )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use async_trait::async_trait;
+  use tokio::sync::mpsc::{channel, Receiver};
+
+  const HEADSET_MAC : &str = "AA:BB:CC:DD:EE:01";
+  const SPEAKER_MAC : &str = "AA:BB:CC:DD:EE:02";
+  const STATE_TIMEOUT : Duration = Duration::from_secs(2);
+
+  fn device_config(name : &str, mac : &str) -> BluetoothDeviceConfig {
+      BluetoothDeviceConfig { name : name.to_string(), mac : mac.to_string(), ..BluetoothDeviceConfig::default() }
+  }
+
+  fn mac(mac : &str) -> MacAddress {
+      MacAddress::from_str(mac).unwrap()
+  }
+
+  /// module over fake backend where all configured devices are paired
+  async fn fake_module(bt_config : &BluetoothConfig) -> (BluetoothModule, Arc<FakeBluetoothBackend>) {
+      let fake = Arc::new( FakeBluetoothBackend::new() );
+      for cfg in &bt_config.devices {
+          fake.add_device(mac(&cfg.mac), &cfg.name);
+      }
+      let bt_module = BluetoothModule::with_backend(bt_config, fake.clone(), Arc::new( FakeCommandRunner::default() )).await;
+      (bt_module, fake)
+  }
+
+  fn two_devices_config() -> BluetoothConfig {
+      BluetoothConfig {
+          devices : vec![ device_config("Headset", HEADSET_MAC), device_config("Speaker", SPEAKER_MAC) ],
+          // no sinks in fake runner, waiting for them is pointless
+          audio : AudioConfig { set_default_sink : false, move_streams : false },
+          ..BluetoothConfig::default()
+      }
+  }
+
+  async fn is_connected(fake : &FakeBluetoothBackend, mac_string : &str) -> bool {
+      fake.get_device_info(&mac(mac_string)).await.unwrap().connected
+  }
+
+  /// waits for the state loop sends until it satisfies the predicate
+  async fn wait_state(receiver : &mut Receiver<BluetoothState>, predicate : impl Fn(&BluetoothState) -> bool) -> BluetoothState {
+      tokio::time::timeout(STATE_TIMEOUT, async {
+          loop {
+              let state = receiver.recv().await.expect("watch loop is finished");
+              if predicate(&state) {
+                  return state;
+              }
+          }
+      }).await.expect("expected state is not reached")
+  }
+
+  /// fake which connects the device right after connection state is read for the first time,
+  /// i.e. event happens while watch loop is building its initial state
+  struct ConnectDuringSnapshot {
+      fake : FakeBluetoothBackend,
+      mac : MacAddress,
+      fired : AtomicBool,
+  }
+
+  #[async_trait]
+  impl BluetoothBackend for ConnectDuringSnapshot {
+      async fn get_devices(&self) -> Result<Vec<BtDeviceInfo>, String> { self.fake.get_devices().await }
+      async fn get_device_info(&self, mac : &MacAddress) -> Result<BtDeviceInfo, String> {
+          let info = self.fake.get_device_info(mac).await;
+          if *mac == self.mac && !self.fired.swap(true, Ordering::SeqCst) {
+              self.fake.set_connected(mac, true);
+          }
+          info
+      }
+      async fn connect(&self, mac : &MacAddress) -> Result<(), String> { self.fake.connect(mac).await }
+      async fn disconnect(&self, mac : &MacAddress) -> Result<(), String> { self.fake.disconnect(mac).await }
+      async fn battery_level(&self, mac : &MacAddress) -> Option<u8> { self.fake.battery_level(mac).await }
+      async fn start_discovery(&self) -> Result<(), String> { self.fake.start_discovery().await }
+      async fn stop_discovery(&self) -> Result<(), String> { self.fake.stop_discovery().await }
+      async fn pair(&self, mac : &MacAddress) -> Result<(), String> { self.fake.pair(mac).await }
+      async fn trust(&self, mac : &MacAddress) -> Result<(), String> { self.fake.trust(mac).await }
+      async fn adapter_state(&self) -> AdapterState { self.fake.adapter_state().await }
+      async fn set_powered(&self, powered : bool) -> Result<(), String> { self.fake.set_powered(powered).await }
+      async fn device_event_stream(&self) -> Result<BoxStream<'static, BtEvent>, String> { self.fake.device_event_stream().await }
+      fn has_battery_events(&self) -> bool { self.fake.has_battery_events() }
+  }
+
+  #[tokio::test]
+  async fn event_during_initial_state_is_not_lost() {
+      let bt_config = BluetoothConfig { devices : vec![ device_config("Headset", HEADSET_MAC) ], ..BluetoothConfig::default() };
+      let fake = FakeBluetoothBackend::new();
+      fake.add_device(mac(HEADSET_MAC), "Headset");
+      let backend = Arc::new( ConnectDuringSnapshot { fake, mac : mac(HEADSET_MAC), fired : AtomicBool::new(false) } );
+      let bt_module = BluetoothModule::with_backend(&bt_config, backend.clone(), Arc::new( FakeCommandRunner::default() )).await;
+
+      let (sender, mut receiver) = channel(100);
+      let watch = tokio::spawn( watch_bluetooth_loop(bt_module, sender) );
+
+      // initial state is read before the event, so it's applied from the stream
+      let first = receiver.recv().await.unwrap();
+      assert!(backend.fired.load(Ordering::SeqCst));
+      assert!(!first.devices[0].is_connected);
+      let state = wait_state(&mut receiver, |s| s.devices[0].is_connected).await;
+      assert!(state.devices[0].is_available);
+      watch.abort();
+  }
+
+  #[test]
+  fn working_hours_within_day() {
+      let policy = ReconnectPolicy::WorkingHours { from_hour : 9, to_hour : 18 };
+      assert!(!is_policy_active(&policy, 8));
+      assert!(is_policy_active(&policy, 9));
+      assert!(is_policy_active(&policy, 17));
+      assert!(!is_policy_active(&policy, 18));
+  }
+
+  #[test]
+  fn working_hours_overnight() {
+      let policy = ReconnectPolicy::WorkingHours { from_hour : 22, to_hour : 6 };
+      assert!(is_policy_active(&policy, 23));
+      assert!(is_policy_active(&policy, 0));
+      assert!(is_policy_active(&policy, 5));
+      assert!(!is_policy_active(&policy, 6));
+      assert!(!is_policy_active(&policy, 21));
+  }
+
+  #[test]
+  fn find_device_id_by_mac() {
+      let devices = vec![
+          BtDeviceInfo { mac : mac(HEADSET_MAC), name : None, paired : true, connected : false, rssi : None },
+          BtDeviceInfo { mac : mac(SPEAKER_MAC), name : None, paired : true, connected : false, rssi : None },
+      ];
+      assert_eq!(find_device_id(&devices, &mac(SPEAKER_MAC)), Ok( mac(SPEAKER_MAC) ));
+      assert!(find_device_id(&devices, &mac("AA:BB:CC:DD:EE:03")).is_err());
+  }
+
+  #[test]
+  fn configured_mac_is_parsed_once() {
+      // MAC in configuration could be written in lower case
+      let device = BluetoothDevice::new(&device_config("Headset", &HEADSET_MAC.to_lowercase()));
+      assert_eq!(device.mac, Some( mac(HEADSET_MAC) ));
+      let device = BluetoothDevice::new(&device_config("Broken", "not a mac"));
+      assert_eq!(device.mac, None);
+  }
+
+  #[tokio::test]
+  async fn malformed_mac_is_not_resolved_again() {
+      let bt_config = BluetoothConfig { devices : vec![ device_config("Headset", HEADSET_MAC), device_config("Broken", "not a mac") ], ..BluetoothConfig::default() };
+      let fake = Arc::new( FakeBluetoothBackend::new() );
+      fake.add_device(mac(HEADSET_MAC), "Headset");
+      let bt_module = BluetoothModule::with_backend(&bt_config, fake, Arc::new( FakeCommandRunner::default() )).await;
+      assert!(!bt_module.has_unresolved_devices());
+  }
+
+  #[tokio::test]
+  async fn missing_device_is_resolved_once_per_resolve_interval() {
+      let bt_config = two_devices_config();
+      let fake = Arc::new( FakeBluetoothBackend::new() );
+      fake.add_device(mac(HEADSET_MAC), "Headset");
+      let bt_module = BluetoothModule::with_backend(&bt_config, fake.clone(), Arc::new( FakeCommandRunner::default() )).await;
+      assert!(bt_module.has_unresolved_devices());
+      // just resolved at startup
+      assert!(!bt_module.is_resolve_due());
+
+      *bt_module.resolved_at.lock().unwrap() = Some( Instant::now() - RESOLVE_INTERVAL );
+      assert!(bt_module.is_resolve_due());
+  }
+
+  #[tokio::test]
+  async fn connect_and_disconnect_commands() {
+      let (bt_module, fake) = fake_module(&two_devices_config()).await;
+      let key = DeviceKey( String::from("Headset") );
+
+      execute_command(&bt_module, HomeCommand::Connect( key.clone() )).await;
+      assert!(is_connected(&fake, HEADSET_MAC).await);
+      assert!(!is_connected(&fake, SPEAKER_MAC).await);
+
+      execute_command(&bt_module, HomeCommand::Disconnect( key )).await;
+      assert!(!is_connected(&fake, HEADSET_MAC).await);
+  }
+
+  #[tokio::test]
+  async fn unpaired_device_is_not_connected() {
+      let bt_config = two_devices_config();
+      let fake = Arc::new( FakeBluetoothBackend::new() );
+      fake.add_device(mac(HEADSET_MAC), "Headset");
+      let bt_module = BluetoothModule::with_backend(&bt_config, fake.clone(), Arc::new( FakeCommandRunner::default() )).await;
+
+      execute_command(&bt_module, HomeCommand::Connect( DeviceKey( String::from("Speaker") ) )).await;
+      let state = bt_module.get_state().await;
+      assert!(state.devices[0].is_available);
+      assert!(!state.devices[1].is_available);
+      assert!(!state.devices[1].is_connected);
+  }
+
+  #[tokio::test]
+  async fn powering_adapter_off_disconnects_devices() {
+      let (bt_module, fake) = fake_module(&two_devices_config()).await;
+      fake.set_connected(&mac(HEADSET_MAC), true);
+
+      execute_command(&bt_module, HomeCommand::SetAdapterPowered( false )).await;
+      let state = bt_module.get_state().await;
+      assert!(!state.adapter.powered);
+      assert!(!state.devices[0].is_connected);
+  }
+
+  #[tokio::test]
+  async fn watch_loop_follows_connection_and_battery() {
+      let (bt_module, fake) = fake_module(&two_devices_config()).await;
+      let (sender, mut receiver) = channel(100);
+      let watch = tokio::spawn( watch_bluetooth_loop(bt_module, sender) );
+      wait_state(&mut receiver, |s| s.devices.iter().all(|d| d.is_available && !d.is_connected)).await;
+
+      fake.set_connected(&mac(SPEAKER_MAC), true);
+      fake.set_battery(&mac(SPEAKER_MAC), Some( 42 ));
+      let state = wait_state(&mut receiver, |s| s.devices[1].battery == Some( 42 )).await;
+      assert!(state.devices[1].is_connected);
+      assert!(!state.devices[0].is_connected);
+
+      // device is powered off outside of dashboard
+      fake.set_connected(&mac(SPEAKER_MAC), false);
+      let state = wait_state(&mut receiver, |s| !s.devices[1].is_connected).await;
+      assert_eq!(state.devices[1].battery, None);
+      watch.abort();
+  }
+
+  #[test]
+  fn paired_device_name_is_unique() {
+      let names = vec![ String::from("Headset"), String::from("Speaker") ];
+      assert_eq!(unique_device_name(&names, String::from("Keyboard"), &mac(HEADSET_MAC)), "Keyboard");
+      assert_eq!(unique_device_name(&names, String::from("Headset"), &mac(HEADSET_MAC)), "Headset (EE:01)");
+      let names = vec![ String::from("Headset"), String::from("Headset (EE:01)") ];
+      assert_eq!(unique_device_name(&names, String::from("Headset"), &mac(HEADSET_MAC)), format!("Headset ({})", HEADSET_MAC));
+  }
+
+  fn exclusive_group_config() -> BluetoothConfig {
+      let mut bt_config = two_devices_config();
+      for cfg in bt_config.devices.iter_mut() {
+          cfg.group = Some( String::from("audio") );
+      }
+      bt_config.devices[1].reconnect = ReconnectPolicy::KeepConnected;
+      bt_config.exclusive_groups = vec![ String::from("audio") ];
+      bt_config
+  }
+
+  #[tokio::test]
+  async fn group_peer_is_disconnected_and_not_reconnected() {
+      let (bt_module, fake) = fake_module(&exclusive_group_config()).await;
+      fake.set_connected(&mac(SPEAKER_MAC), true);
+
+      execute_command(&bt_module, HomeCommand::Connect( DeviceKey( String::from("Headset") ) )).await;
+      assert!(is_connected(&fake, HEADSET_MAC).await);
+      assert!(!is_connected(&fake, SPEAKER_MAC).await);
+
+      // speaker keeps connected by policy, but it was kicked out of the group on purpose
+      let mut bt_state = bt_module.get_state().await;
+      bt_module.reconnect_devices(&mut bt_state);
+      tokio::task::yield_now().await;
+      assert!(!is_connected(&fake, SPEAKER_MAC).await);
+      assert!(bt_module.reconnects.lock().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn failed_connect_keeps_group_peer() {
+      let (bt_module, fake) = fake_module(&exclusive_group_config()).await;
+      fake.set_connected(&mac(SPEAKER_MAC), true);
+      fake.set_reachable(&mac(HEADSET_MAC), false);
+
+      execute_command(&bt_module, HomeCommand::Connect( DeviceKey( String::from("Headset") ) )).await;
+      assert!(!is_connected(&fake, HEADSET_MAC).await);
+      assert!(is_connected(&fake, SPEAKER_MAC).await);
+  }
+
+  #[tokio::test]
+  async fn connected_device_becomes_audio_output() {
+      let mut bt_config = two_devices_config();
+      bt_config.audio = AudioConfig { set_default_sink : true, move_streams : true };
+      let fake = Arc::new( FakeBluetoothBackend::new() );
+      let runner = Arc::new( FakeCommandRunner::default() );
+      for cfg in &bt_config.devices {
+          fake.add_device(mac(&cfg.mac), &cfg.name);
+          runner.add_bluetooth_sink(&mac(&cfg.mac));
+      }
+      runner.add_sink_input(7);
+      let bt_module = BluetoothModule::with_backend(&bt_config, fake.clone(), runner.clone()).await;
+
+      bt_module.switch_audio_output(&mac(SPEAKER_MAC)).await.unwrap();
+      let sink = String::from("bluez_output.AA_BB_CC_DD_EE_02.1");
+      assert_eq!(bt_module.audio.default_sink().await, Ok( sink.clone() ));
+      assert!(runner.calls().contains( &vec![ String::from("move-sink-input"), String::from("7"), sink ] ));
+
+      let mut bt_state = bt_module.get_state().await;
+      bt_module.update_audio_output(&mut bt_state).await;
+      assert!(!bt_state.devices[0].is_audio_output);
+      assert!(bt_state.devices[1].is_audio_output);
+  }
+}
+
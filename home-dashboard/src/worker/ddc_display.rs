@@ -1,45 +1,234 @@
 use log;
 use ddc_hi::{Ddc, Display};
-use tokio::sync::mpsc::Sender;
-use tokio::sync::mpsc::error::TrySendError;
+use mccs_db::ValueType;
+use serde::{Serialize, Deserialize};
+use tokio::sync::mpsc::{Sender, Receiver};
+use tokio::sync::mpsc::error::{TrySendError, TryRecvError};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use crate::interface::{HomeCommand, DisplayKey, DisplaysConfig, DisplayConfig, PresetConfig};
+
+// commands are checked more often than displays are polled, so buttons respond quickly
+const COMMAND_CHECK_INTERVAL : Duration = Duration::from_millis(100);
+// display needs a while to apply new preset before it's read back
+const PRESET_SETTLE_TIME : Duration = Duration::from_millis(500);
+// displays are enumerated again after failures, but not more often, reading capabilities takes seconds
+const ENUMERATE_INTERVAL : Duration = Duration::from_secs(30);
+
+/// MCCS features shown on display tile, if display supports them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisplayFeature {
+    Brightness,
+    Contrast,
+    Volume,
+    ColorPreset,
+    Input,
+    PowerMode,
+}
+
+impl DisplayFeature {
+    // in order of tile rows
+    pub const ALL : [DisplayFeature; 6] = [
+        DisplayFeature::Brightness,
+        DisplayFeature::Contrast,
+        DisplayFeature::Volume,
+        DisplayFeature::ColorPreset,
+        DisplayFeature::Input,
+        DisplayFeature::PowerMode,
+    ];
+
+    pub fn code(&self) -> u8 {
+        match self {
+            DisplayFeature::Brightness => 0x10,
+            DisplayFeature::Contrast => 0x12,
+            DisplayFeature::Volume => 0x62,
+            DisplayFeature::ColorPreset => 0x14,
+            DisplayFeature::Input => 0x60,
+            DisplayFeature::PowerMode => 0xD6,
+        }
+    }
+
+    /// value in range 0..=maximum, otherwise one of listed values
+    pub fn is_continuous(&self) -> bool {
+        matches!(self, DisplayFeature::Brightness | DisplayFeature::Contrast | DisplayFeature::Volume)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureState {
+    pub feature : DisplayFeature,
+    pub value : Option<u16>,
+    // of continuous feature, usually 100
+    pub max : Option<u16>,
+    // values and names of non-continuous feature, from configuration or capabilities
+    pub choices : Vec<(u16, String)>,
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Preset {
-    Standard,
-    Comfort,
-    Movie,
-    Game,
-    Unknown{ val_dc: u16, val_f0 : u16 },
+    // PresetConfig::name
+    Named(String),
+    // register values which match no configured preset
+    Unknown(Vec<(u8, u16)>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct DisplayState {
-  pub brightness : Option<u16>,
+  pub key : DisplayKey,
+  // DisplayConfig::name, or model of display if it's not configured
+  pub name : String,
+  // supported by display, in order of DisplayFeature::ALL
+  pub features : Vec<FeatureState>,
   pub preset: Option<Preset>,
+  // PresetConfig::name of presets which can be set, in order of buttons
+  pub presets : Vec<String>,
+  // failure of the last command, None if it succeeded
+  pub error : Option<String>,
+}
+
+struct TrackedDisplay {
+    display : Display,
+    cfg : DisplayConfig,
+    state : DisplayState,
+    polled_at : Option<Instant>,
+}
+
+impl TrackedDisplay {
+    fn new(mut display : Display, cfg : &DisplaysConfig) -> Self {
+        let key = display_key(&display);
+        let mut cfg = cfg.displays.iter()
+            .find(|c| Some( &c.id ) == display.info.serial_number.as_ref() || Some( &c.id ) == display.info.model_name.as_ref())
+            .cloned()
+            .unwrap_or_else(|| DisplayConfig::unconfigured( display.info.model_name.clone().unwrap_or(key.0.clone()) ));
+
+        let features = supported_features(&mut display, &mut cfg);
+        let state = DisplayState {
+            key,
+            name : cfg.name.clone(),
+            features,
+            preset : None,
+            presets : cfg.presets.iter().map(|p| p.name.clone()).collect(),
+            error : None,
+        };
+        TrackedDisplay { display, cfg, state, polled_at : None }
+    }
+
+    fn is_poll_due(&self) -> bool {
+        match self.polled_at {
+            None => true,
+            Some( t ) => t.elapsed() >= Duration::from_millis(self.cfg.poll_interval_ms),
+        }
+    }
+
+    /// false if some of the features can't be read
+    fn poll(&mut self) -> bool {
+        self.polled_at = Some( Instant::now() );
+        let mut read = true;
+        for f in &mut self.state.features {
+            match get_feature(&mut self.display, f.feature) {
+                Some( (value, max) ) => {
+                    f.value = Some( value );
+                    f.max = Some( max );
+                },
+                None => {
+                    f.value = None;
+                    read = false;
+                },
+            }
+        }
+        self.state.preset = get_preset(&mut self.display, &self.cfg.presets);
+        log::info!("{}: {:?}, Preset {:?}", self.state.name,
+            self.state.features.iter().map(|f| (f.feature, f.value)).collect::<Vec<_>>(), self.state.preset);
+        read
+    }
+
+    fn execute_command(&mut self, cmd : HomeCommand) -> Result<(), String>
+    {
+        log::debug!("Got display CMD: {:?}", cmd);
+        // result of the command is shown right away
+        self.polled_at = None;
+        match cmd {
+            HomeCommand::SetDisplayFeature( _, feature, val ) =>
+                self.display.handle.set_vcp_feature(feature.code(), val)
+                  .map_err(|e| format!("Failed to set {:?} to {}: {}", feature, val, e)),
+            HomeCommand::SetPreset( _, name ) =>
+                set_preset(&mut self.display, &self.cfg.presets, &name).map_err(|e| format!("Failed to set preset {}: {}", name, e)),
+            cmd => {
+                log::warn!("{:?} is not a display command", cmd);
+                Ok(())
+            },
+        }
+    }
 }
 
+/// all DDC/CI displays, configured ones first in order of configuration.
+/// Failed reads and commands make them enumerated again, display may be reconnected to another bus.
 pub fn watch_ddc_display_loop(
-    display_sender : Sender<DisplayState>) -> Result<(), String>
+    display_sender : Sender<Vec<DisplayState>>,
+    mut command_receiver : Receiver<HomeCommand>,
+    cfg : DisplaysConfig) -> Result<(), String>
 {
-    let prefered_model = "DELL U3421WE";
-    let mut displays : Vec<Display> = Display::enumerate();
-    let display : &mut Display  = find_display(&mut displays, &prefered_model)?;
-
-    //display.update_capabilities().unwrap();
-    log::info!("Found display {}", display_string(display));
+    let mut displays = enumerate_displays(&cfg);
+    if displays.is_empty() {
+        return Err( String::from("Can't find any DDC displays") );
+    }
+    let mut enumerated_at = Instant::now();
+    let mut enumeration_due = false;
 
-    let mut prev_ds : Option<DisplayState> = None;
+    let mut prev_states : Option<Vec<DisplayState>> = None;
 
     loop {
-        let brightness = get_brightness(display);
-        let preset = get_preset(display);
-        log::info!("Brightness: {:?}, Preset {:?}", brightness, preset);
+        loop {
+            match command_receiver.try_recv() {
+                Ok( cmd ) => {
+                    let Some( key ) = command_display(&cmd) else {
+                        log::warn!("{:?} is not a display command", cmd);
+                        continue;
+                    };
+                    match displays.iter_mut().find(|d| &d.state.key == key) {
+                        None => log::warn!("Display {:?} is not found, ignoring {:?}", key, cmd),
+                        Some( d ) => {
+                            let res = d.execute_command(cmd);
+                            if let Err( e ) = &res {
+                                log::warn!("{}", e);
+                                enumeration_due = true;
+                            }
+                            d.state.error = res.err();
+                        },
+                    }
+                },
+                Err( TryRecvError::Empty ) => break,
+                Err( TryRecvError::Disconnected ) => {
+                    log::warn!("Display command channel is closed. Probably execute_command_loop is dead now. Exiting....");
+                    return Ok(());
+                },
+            }
+        }
 
-        let new_ds = DisplayState{ brightness, preset };
-        if prev_ds.is_none() || prev_ds.as_ref().unwrap() != &new_ds {
-            match display_sender.try_send( new_ds.clone() ) {
+        if enumeration_due && enumerated_at.elapsed() >= ENUMERATE_INTERVAL {
+            // errors of commands are shown until the next command
+            let errors : Vec<(DisplayKey, String)> = displays.iter()
+                .filter_map(|d| d.state.error.clone().map(|e| (d.state.key.clone(), e)))
+                .collect();
+            // handles of the old ones are closed first
+            displays.clear();
+            displays = enumerate_displays(&cfg);
+            for d in &mut displays {
+                d.state.error = errors.iter().find(|(key, _)| key == &d.state.key).map(|(_, e)| e.clone());
+            }
+            enumerated_at = Instant::now();
+            enumeration_due = displays.is_empty();
+        }
+
+        for d in displays.iter_mut().filter(|d| d.is_poll_due()) {
+            if !d.poll() {
+                enumeration_due = true;
+            }
+        }
+
+        let states : Vec<DisplayState> = displays.iter().map(|d| d.state.clone()).collect();
+        if prev_states.as_ref() != Some( &states ) {
+            match display_sender.try_send( states.clone() ) {
                 Ok(()) => (),
                 Err( TrySendError::Full( _ ) ) => log::warn!("Failed to send display state, update_state_loop is not consuming it!"),
                 Err( TrySendError::Closed( _ ) ) => {
@@ -47,82 +236,158 @@ pub fn watch_ddc_display_loop(
                     break;
                 },
             }
-            prev_ds = Some( new_ds );
+            prev_states = Some( states );
         };
-        sleep(Duration::from_millis(1000));
+        sleep(COMMAND_CHECK_INTERVAL);
     }
 
   log::warn!("watch_ddc_display_loop finsied");
   Ok(())
 }
 
-fn get_brightness(display : &mut Display) -> Option<u16>
+fn enumerate_displays(cfg : &DisplaysConfig) -> Vec<TrackedDisplay>
 {
-    match display.handle.get_vcp_feature(0x10) {
-        Err( e ) => {
-            log::warn!("get_brightness error: {:?}", e);
-            None
-        }
-        Ok( v ) => Some( v.value() ),
+    let mut displays : Vec<TrackedDisplay> = Display::enumerate().into_iter()
+        .map(|d| TrackedDisplay::new(d, cfg))
+        .collect();
+    displays.sort_by_key(|d| cfg.displays.iter().position(|c| c.id == d.cfg.id).unwrap_or(usize::MAX));
+
+    for d in &displays {
+        log::info!("Found display {} as {} with {:?}", display_string(&d.display), d.state.name,
+            d.state.features.iter().map(|f| f.feature).collect::<Vec<_>>());
     }
+    displays
 }
 
-fn set_brightness(display : &mut Display, val : u16) -> Result<(), String>
+fn command_display(cmd : &HomeCommand) -> Option<&DisplayKey>
 {
-    display.handle.set_vcp_feature(0x10, val).map_err(|e| e.to_string())
+    match cmd {
+        HomeCommand::SetDisplayFeature( key, _, _ ) | HomeCommand::SetPreset( key, _ ) => Some( key ),
+        _ => None,
+    }
 }
 
-fn get_preset(display : &mut Display) -> Option<Preset>
+/// serial number is unique, but not every display reports it, then model and connection
+/// tell apart identical displays
+fn display_key(display : &Display) -> DisplayKey
 {
-    match display.handle.get_vcp_feature(0xDC) {
-        Err( e ) => {
-            log::warn!("get_preset:get_vcp_feature 0xDC error: {:?}", e);
-            None
+    let info = &display.info;
+    let key = info.serial_number.clone().filter(|s| !s.is_empty())
+        .unwrap_or_else(|| match &info.model_name {
+            Some( model ) => format!("{} {}", model, info.id),
+            None => info.id.clone(),
+        });
+    DisplayKey( key )
+}
+
+/// Features listed in MCCS capabilities of the display, presets of the configuration which it can set.
+/// If display doesn't report capabilities, features are probed instead and all presets are kept.
+fn supported_features(display : &mut Display, cfg : &mut DisplayConfig) -> Vec<FeatureState>
+{
+    let has_capabilities = match display.update_capabilities() {
+        // ddc-hi fills MCCS database only for capabilities with MCCS version, it would be empty otherwise
+        Ok( () ) if display.info.mccs_version.is_none() => {
+            log::warn!("Capabilities of display {} have no MCCS version, probing features", display_string(display));
+            false
         },
-        Ok( val_dc ) => {
-            match display.handle.get_vcp_feature(0xF0) {
-                Err( e ) => {
-                    log::warn!("get_preset:get_vcp_feature 0xF0 error: {:?}", e);
-                    None
-                },
-                Ok( val_f0 ) => Some (
-                    match (val_dc.value(), val_f0.value()) {
-                        (0, 0) => Preset::Standard,
-                        (0, 0xC) => Preset::Comfort,
-                        (3, 0) => Preset::Movie,
-                        (5, 0) => Preset::Game,
-                        (val_dc, val_f0) => Preset::Unknown{val_dc, val_f0},
-                    }),
-              }
+        Ok( () ) => true,
+        Err( e ) => {
+            log::warn!("Failed to get capabilities of display {}, probing features: {}", display_string(display), e);
+            false
         },
+    };
+
+    if has_capabilities {
+        let database = &display.info.mccs_database;
+        cfg.presets.retain(|p| {
+            let supported = p.registers.iter().all(|r| database.get(r.code).is_some());
+            if !supported {
+                log::warn!("Preset {} is not supported by display {}", p.name, cfg.id);
+            }
+            supported
+        });
     }
+
+    let mut features = Vec::new();
+    for feature in DisplayFeature::ALL {
+        let mut choices = Vec::new();
+        if has_capabilities {
+            let Some( descriptor ) = display.info.mccs_database.get(feature.code()) else { continue; };
+            if let ValueType::NonContinuous { values, .. } = &descriptor.ty {
+                choices = values.iter()
+                  .map(|(value, name)| (*value as u16, name.clone().unwrap_or_else(|| format!("{:#x}", value))))
+                  .collect();
+            }
+        } else if get_feature(display, feature).is_none() {
+            continue;
+        }
+
+        // names from configuration are better than MCCS ones, i.e. "Desktop DP" instead of "DisplayPort-1"
+        if feature == DisplayFeature::Input && !cfg.inputs.is_empty() {
+            choices = cfg.inputs.iter().map(|i| (i.value, i.name.clone())).collect();
+        }
+        features.push( FeatureState { feature, value : None, max : None, choices } );
+    }
+    features
 }
 
-fn set_preset(display : &mut Display, preset : Preset) -> Result<(), String>
+/// current value and maximum
+fn get_feature(display : &mut Display, feature : DisplayFeature) -> Option<(u16, u16)>
 {
-    match preset {
-        Preset::Standard => display.handle.set_vcp_feature(0xDC, 0).map_err(|e| e.to_string()),
-        Preset::Comfort  => display.handle.set_vcp_feature(0xF0, 0xC).map_err(|e| e.to_string()),
-        Preset::Movie => display.handle.set_vcp_feature(0xDC, 3).map_err(|e| e.to_string()),
-        Preset::Game => display.handle.set_vcp_feature(0xDC, 5).map_err(|e| e.to_string()),
-        Preset::Unknown{..} => Err(String::from("seting of Unknown  presets are not supported!")),
+    match display.handle.get_vcp_feature(feature.code()) {
+        Err( e ) => {
+            log::warn!("get_feature {:?} error: {:?}", feature, e);
+            None
+        },
+        Ok( v ) if feature.is_continuous() => Some( (v.value(), v.maximum()) ),
+        // non-continuous values fit into low byte, some displays put other data into high one
+        Ok( v ) => Some( (v.value() & 0xFF, v.maximum()) ),
     }
 }
 
-fn find_display<'a>(displays : &'a mut [Display], prefered_model: &str) -> Result<&'a mut Display, String>
+/// None if display has no presets or they can't be read
+fn get_preset(display : &mut Display, presets : &[PresetConfig]) -> Option<Preset>
 {
-    if displays.is_empty() {
-        return Err( String::from("Can't find any DDC displays") );
+    let mut codes : Vec<u8> = presets.iter().flat_map(|p| p.registers.iter().map(|r| r.code)).collect();
+    codes.sort();
+    codes.dedup();
+    if codes.is_empty() {
+        return None;
     }
 
-    let idx =
-        if let Some(i) = displays.iter().position(|d| d.info.model_name.is_some() && d.info.model_name.as_ref().unwrap() == prefered_model) {
-            i
-        } else {
-            0
-        };
+    let mut values = Vec::new();
+    for code in codes {
+        match display.handle.get_vcp_feature(code) {
+            Err( e ) => {
+                log::warn!("get_preset:get_vcp_feature {:#x} error: {:?}", code, e);
+                return None;
+            },
+            Ok( v ) => values.push( (code, v.value()) ),
+        }
+    }
+
+    let preset = presets.iter()
+        .find(|p| p.registers.iter().all(|r| values.contains( &(r.code, r.value) )))
+        .map(|p| Preset::Named( p.name.clone() ));
+    Some( preset.unwrap_or(Preset::Unknown(values)) )
+}
 
-    Ok( &mut displays[idx] )
+/// writes all registers of the preset, so any preset can be switched to any other one, and reads them back
+fn set_preset(display : &mut Display, presets : &[PresetConfig], name : &str) -> Result<(), String>
+{
+    let Some( preset ) = presets.iter().find(|p| p.name == name) else {
+        return Err(String::from("preset is not configured for the display"));
+    };
+    for r in &preset.registers {
+        display.handle.set_vcp_feature(r.code, r.value).map_err(|e| e.to_string())?;
+    }
+
+    sleep(PRESET_SETTLE_TIME);
+    match get_preset(display, presets) {
+        Some( Preset::Named( n ) ) if n == name => Ok(()),
+        Some( p ) => Err( format!("display reports {:?} instead", p) ),
+        None => Err( String::from("failed to read preset back") ),
+    }
 }
 
 fn display_string(display :&Display) -> String
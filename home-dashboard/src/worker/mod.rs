@@ -23,20 +23,49 @@ pub async fn worker_thread(sender : Sender<HomeState>, receiver : Receiver<HomeC
 
 pub async fn worker_thread_prime(sender : Sender<HomeState>, receiver : Receiver<HomeCommand>, ctx : Context, cfg : HomeDashboardConfig) -> Result<(), String> {
 
-  let bt_module = BluetoothModule::new(&cfg.bt_config).await?;
+  let bt_module = match BluetoothModule::new(&cfg.bt_config).await {
+    Err( e ) => {
+      log::error!("Bluetooth is unavailable: {}", e);
+      None
+    },
+    Ok( m ) => Some( m ),
+  };
 
   const MAX_NUM_MESSAGES : usize = 5;
   let (bt_sender, bt_receiver) = channel::<BluetoothState>(MAX_NUM_MESSAGES);
   let (netatmo_sender, netatmo_receiver) = channel::<NetatmoData>(MAX_NUM_MESSAGES);
-  let (display_sender, display_receiver) = channel::<DisplayState>(MAX_NUM_MESSAGES);
+  let (display_sender, display_receiver) = channel::<Vec<DisplayState>>(MAX_NUM_MESSAGES);
+  let (netatmo_command_sender, netatmo_command_receiver) = channel::<HomeCommand>(MAX_NUM_MESSAGES);
+  let (display_command_sender, display_command_receiver) = channel::<HomeCommand>(MAX_NUM_MESSAGES);
 
+  let bt_module_watch = bt_module.clone();
+  let bt_config = cfg.bt_config.clone();
   let h1 = tokio::task::spawn( update_state_loop(sender, bt_receiver, netatmo_receiver, display_receiver, ctx) );
-  let h3 = tokio::task::spawn( watch_bluetooth_loop(bt_module.clone(), bt_sender) );
-  let h2 = tokio::task::spawn( execute_command_loop(receiver, bt_module) );
-  let h4 = tokio::task::spawn ( watch_netatmo_loop(netatmo_sender, cfg.connect_config.clone()) );
+  let h3 = tokio::task::spawn( async move {
+      match bt_module_watch {
+        Some( bt_module ) => watch_bluetooth_loop(bt_module, bt_sender).await,
+        None => {
+          // still show configured devices, but as unavailable ones
+          if let Err( e ) = bt_sender.try_send( unavailable_bt_state(&bt_config) ) {
+            log::warn!("Failed to send BT data: {:?}", e);
+          }
+          Ok(())
+        },
+      }
+    });
+  let h2 = tokio::task::spawn( execute_command_loop(receiver, bt_module, netatmo_command_sender, display_command_sender) );
+  let netatmo_dir = match netatmo::data_dir() {
+    Err( e ) => {
+      log::warn!("Netatmo token and history are kept in memory only: {}", e);
+      None
+    },
+    Ok( d ) => Some( d ),
+  };
+  let h4 = tokio::task::spawn ( watch_netatmo_loop(netatmo_sender, netatmo_command_receiver, cfg.connect_config.clone(), cfg.netatmo_config.clone(), netatmo_dir) );
+  let displays_config = cfg.displays_config.clone();
   let h5 = thread::spawn( ||
       {
-          if let Err( e ) = watch_ddc_display_loop(display_sender) {
+          if let Err( e ) = watch_ddc_display_loop(display_sender, display_command_receiver, displays_config) {
               log::error!("watch_ddc_display_loop finised with error: {}", e);
           };
        } );
@@ -48,8 +77,10 @@ pub async fn worker_thread_prime(sender : Sender<HomeState>, receiver : Receiver
   if let Err( e ) = h2.await {
     log::warn!("execute_command_loop task is faield... {:?}", e);
   }
-  if let Err( e ) = h3.await {
-    log::warn!("watch_bluetooth_loop task is faield... {:?}", e);
+  match h3.await {
+    Err( e ) => log::warn!("watch_bluetooth_loop task is faield... {:?}", e),
+    Ok( Err( e ) ) => log::warn!("watch_bluetooth_loop finished with error: {}", e),
+    Ok( Ok( () ) ) => (),
   }
   if let Err( e ) = h4.await {
     log::warn!("watch_netatmo_loop task is faield... {:?}", e);
@@ -65,7 +96,7 @@ async fn update_state_loop(
   sender : Sender<HomeState>,
   mut bt_receiver : Receiver<BluetoothState>,
   mut netatmo_receiver : Receiver<NetatmoData>,
-  mut display_receiver : Receiver<DisplayState>,
+  mut display_receiver : Receiver<Vec<DisplayState>>,
   egui_ctx : Context) -> Result<(), String>
 {
   let mut state = HomeState::default();
@@ -85,12 +116,12 @@ async fn update_state_loop(
           state.bt_state = bt_state;
       }
       Some( netatmo_data ) = netatmo_receiver.recv() => {
-          state.weather_data = netatmo_data.weather_station;
-          state.child_room_data = netatmo_data.child_room_data;
-          state.office_room_data = netatmo_data.office_room_data;
+          state.locations = netatmo_data.locations;
+          state.heating = netatmo_data.heating;
+          state.netatmo_error = netatmo_data.error;
       }
-      Some( display_state ) = display_receiver.recv() => {
-          state.display_state = Some( display_state );
+      Some( displays ) = display_receiver.recv() => {
+          state.displays = displays;
       }
      else => { break; }
     }
@@ -103,12 +134,27 @@ async fn update_state_loop(
 
 async fn execute_command_loop(
   mut receiver : Receiver<HomeCommand>,
-  bt_module : BluetoothModule,
+  bt_module : Option<BluetoothModule>,
+  netatmo_sender : Sender<HomeCommand>,
+  display_sender : Sender<HomeCommand>,
   )
 {
   loop {
       match receiver.recv().await {
-      Some( cmd ) => execute_command( &bt_module, cmd ).await,
+      Some( cmd @ (HomeCommand::SetRoomSetpoint( .. ) | HomeCommand::SetThermMode( _ ) | HomeCommand::RefreshNetatmo) ) => {
+        if let Err( e ) = netatmo_sender.try_send( cmd ) {
+          log::warn!("Failed to pass command to watch_netatmo_loop: {:?}", e);
+        }
+      },
+      Some( cmd @ (HomeCommand::SetDisplayFeature( .. ) | HomeCommand::SetPreset( .. )) ) => {
+        if let Err( e ) = display_sender.try_send( cmd ) {
+          log::warn!("Failed to pass command to watch_ddc_display_loop: {:?}", e);
+        }
+      },
+      Some( cmd ) => match &bt_module {
+        Some( bt_module ) => execute_command( bt_module, cmd ).await,
+        None => log::warn!("Bluetooth is unavailable, ignoring {:?}", cmd),
+      },
       None => {
         log::warn!("Failed to receiver data, probably GUI is dead. Exiting...");
         break;
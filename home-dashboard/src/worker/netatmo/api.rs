@@ -0,0 +1,343 @@
+//! Typed Netatmo weather and energy API, with every module of the station, its type and reachability.
+//! This is the intended home of API requests, including getmeasure for history: netatmo_connect is kept
+//! for authorization only, so its own untyped getstationsdata and gethomecoachsdata are not used anymore.
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::interface::NetatmoErrorKind;
+
+// all requests sent so far, for RequestBudget
+static REQUESTS_SENT : AtomicU64 = AtomicU64::new(0);
+
+// values of Module::module_type
+pub const OUTDOOR_MODULE : &str = "NAModule1";
+pub const WIND_GAUGE : &str = "NAModule2";
+pub const RAIN_GAUGE : &str = "NAModule3";
+pub const INDOOR_MODULE : &str = "NAModule4";
+
+// error codes of Netatmo error body
+const INVALID_ACCESS_TOKEN : i64 = 2;
+const ACCESS_TOKEN_EXPIRED : i64 = 3;
+const USER_USAGE_REACHED : i64 = 26;
+
+#[derive(Debug)]
+pub enum ApiError {
+  // connection refused, timeout and the like, nothing is received
+  Network(String),
+  // answer with HTTP error status, code and message are taken from its body if it's Netatmo error
+  Status { method : String, status : reqwest::StatusCode, code : Option<i64>, message : String },
+  // answer is received but it's not what is expected
+  Answer(String),
+}
+
+impl ApiError {
+  pub fn kind(&self) -> NetatmoErrorKind {
+      match self {
+        ApiError::Network( _ ) => NetatmoErrorKind::Network,
+        // Netatmo answers with 403 and code 26 when quota is exceeded
+        ApiError::Status { status, code, .. } if status.as_u16() == 429 || *code == Some( USER_USAGE_REACHED ) => NetatmoErrorKind::RateLimit,
+        ApiError::Status { status, code, .. } if status.as_u16() == 401 || *code == Some( INVALID_ACCESS_TOKEN ) || *code == Some( ACCESS_TOKEN_EXPIRED ) => NetatmoErrorKind::Auth,
+        // 403 without token error code comes from proxies and the like, the stored token is still fine
+        ApiError::Status { .. } | ApiError::Answer( _ ) => NetatmoErrorKind::Server,
+      }
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+      match self {
+        ApiError::Network( e ) | ApiError::Answer( e ) => write!(f, "{}", e),
+        ApiError::Status { method, status, code : Some( code ), message } => write!(f, "{} failed with {}, error {}: {}", method, status, code, message),
+        ApiError::Status { method, status, code : None, message } => write!(f, "{} failed with {}: {}", method, status, message),
+      }
+  }
+}
+
+impl From<ApiError> for String {
+  fn from(e : ApiError) -> String {
+      e.to_string()
+  }
+}
+
+/// body of Netatmo answer with HTTP error status
+#[derive(Deserialize, Debug)]
+struct ErrorAnswer {
+  error : ErrorBody,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorBody {
+  code : i64,
+  #[serde(default)]
+  message : String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StationsData {
+  pub body : StationsBody,
+  pub time_server : i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomeCoachsData {
+  pub body : StationsBody,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StationsBody {
+  pub devices : Vec<Station>,
+}
+
+/// main (indoor) module with modules attached to it, or home coach which has no modules
+#[derive(Deserialize, Debug)]
+pub struct Station {
+  #[serde(rename = "_id")]
+  pub id : String,
+  #[serde(default)]
+  pub station_name : String,
+  pub reachable : Option<bool>,
+  // absent when station is unreachable
+  pub dashboard_data : Option<DashboardData>,
+  #[serde(default)]
+  pub modules : Vec<Module>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Module {
+  #[serde(rename = "_id")]
+  pub id : String,
+  #[serde(rename = "type")]
+  pub module_type : String,
+  #[serde(default)]
+  pub module_name : String,
+  pub reachable : Option<bool>,
+  // unix time of last message from module
+  pub last_seen : Option<i64>,
+  pub dashboard_data : Option<DashboardData>,
+}
+
+/// union of measurements of all module types, each type fills its own ones
+#[derive(Deserialize, Debug, Default)]
+pub struct DashboardData {
+  // unix time of measurement
+  pub time_utc : Option<i64>,
+  #[serde(rename = "Temperature")]
+  pub temperature : Option<f32>,
+  pub temp_trend : Option<String>,
+  #[serde(rename = "Humidity")]
+  pub humidity : Option<i32>,
+  #[serde(rename = "CO2")]
+  pub co2 : Option<i32>,
+  #[serde(rename = "Noise")]
+  pub noise : Option<i32>,
+  #[serde(rename = "Pressure")]
+  pub pressure : Option<f32>,
+  pub pressure_trend : Option<String>,
+  // mm
+  pub sum_rain_1 : Option<f32>,
+  pub sum_rain_24 : Option<f32>,
+  // km/h and degrees
+  #[serde(rename = "WindStrength")]
+  pub wind_strength : Option<i32>,
+  #[serde(rename = "WindAngle")]
+  pub wind_angle : Option<i32>,
+  #[serde(rename = "GustStrength")]
+  pub gust_strength : Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomesData {
+  pub body : HomesBody,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomesBody {
+  pub homes : Vec<Home>,
+}
+
+/// topology of Netatmo Energy home, see HomeStatus for current values
+#[derive(Deserialize, Debug)]
+pub struct Home {
+  pub id : String,
+  #[serde(default)]
+  pub name : String,
+  #[serde(default)]
+  pub rooms : Vec<HomeRoom>,
+  // "schedule", "away" or "hg"
+  pub therm_mode : Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomeRoom {
+  pub id : String,
+  #[serde(default)]
+  pub name : String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomeStatusData {
+  pub body : HomeStatusBody,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomeStatusBody {
+  pub home : HomeStatus,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HomeStatus {
+  #[serde(default)]
+  pub rooms : Vec<RoomStatus>,
+  #[serde(default)]
+  pub modules : Vec<ModuleStatus>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoomStatus {
+  pub id : String,
+  pub reachable : Option<bool>,
+  pub therm_measured_temperature : Option<f32>,
+  pub therm_setpoint_temperature : Option<f32>,
+  // "schedule", "manual", "away", "hg", "off" or "max"
+  pub therm_setpoint_mode : Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModuleStatus {
+  #[serde(rename = "type")]
+  pub module_type : String,
+  // relay and thermostat only
+  pub boiler_status : Option<bool>,
+}
+
+/// answer of methods which change something
+#[derive(Deserialize, Debug)]
+struct StatusAnswer {
+  status : String,
+}
+
+/// getmeasure answer with optimize=false, values are in order of requested types, null if there is no data
+#[derive(Deserialize, Debug)]
+pub struct MeasureData {
+  pub body : HashMap<String, Vec<Option<f32>>>,
+}
+
+/// parameters of getmeasure, scale is i.e. "30min", types are i.e. ["temperature", "co2"]
+pub struct MeasureQuery<'a> {
+  pub device_id : &'a str,
+  // None for the device itself
+  pub module_id : Option<&'a str>,
+  pub scale : &'a str,
+  pub types : &'a [&'a str],
+  // unix time
+  pub date_begin : i64,
+}
+
+pub async fn get_stations_data(client : &reqwest::Client, api_url : &str, access_token : &str, timeout : &Option<Duration>) -> Result<StationsData, ApiError> {
+  get(client, api_url, "getstationsdata", &[], access_token, timeout).await
+}
+
+pub async fn get_homecoachs_data(client : &reqwest::Client, api_url : &str, access_token : &str, timeout : &Option<Duration>) -> Result<HomeCoachsData, ApiError> {
+  get(client, api_url, "gethomecoachsdata", &[], access_token, timeout).await
+}
+
+pub async fn get_homes_data(client : &reqwest::Client, api_url : &str, access_token : &str, timeout : &Option<Duration>) -> Result<HomesData, ApiError> {
+  get(client, api_url, "homesdata", &[], access_token, timeout).await
+}
+
+pub async fn get_home_status(client : &reqwest::Client, api_url : &str, access_token : &str, home_id : &str, timeout : &Option<Duration>) -> Result<HomeStatusData, ApiError> {
+  get(client, api_url, "homestatus", &[("home_id", home_id.to_string())], access_token, timeout).await
+}
+
+/// manual set-point of the room until the end of home's default duration
+pub async fn set_room_thermpoint(
+  client : &reqwest::Client,
+  api_url : &str,
+  access_token : &str,
+  home_id : &str,
+  room_id : &str,
+  temperature : f32,
+  timeout : &Option<Duration>) -> Result<(), ApiError>
+{
+  let params = [
+    ("home_id", home_id.to_string()),
+    ("room_id", room_id.to_string()),
+    ("mode", String::from("manual")),
+    ("temp", temperature.to_string()),
+  ];
+  post(client, api_url, "setroomthermpoint", &params, access_token, timeout).await
+}
+
+/// mode is "schedule", "away" or "hg"
+pub async fn set_therm_mode(client : &reqwest::Client, api_url : &str, access_token : &str, home_id : &str, mode : &str, timeout : &Option<Duration>) -> Result<(), ApiError> {
+  let params = [("home_id", home_id.to_string()), ("mode", mode.to_string())];
+  post(client, api_url, "setthermmode", &params, access_token, timeout).await
+}
+
+/// measurements of device, or its module, since date_begin.
+/// Returned points are (unix time, values in order of types), oldest first.
+pub async fn get_measure(client : &reqwest::Client, api_url : &str, access_token : &str, measure : &MeasureQuery<'_>, timeout : &Option<Duration>) -> Result<Vec<(i64, Vec<Option<f32>>)>, ApiError> {
+  let mut query = vec![
+    ("device_id", measure.device_id.to_string()),
+    ("scale", measure.scale.to_string()),
+    ("type", measure.types.join(",")),
+    ("date_begin", measure.date_begin.to_string()),
+    ("optimize", String::from("false")),
+  ];
+  if let Some( module_id ) = measure.module_id {
+      query.push( ("module_id", module_id.to_string()) );
+  }
+
+  let res : MeasureData = get(client, api_url, "getmeasure", &query, access_token, timeout).await?;
+  let mut points : Vec<(i64, Vec<Option<f32>>)> = res.body.into_iter()
+    .filter_map(|(time, values)| time.parse::<i64>().ok().map(|t| (t, values)))
+    .collect();
+  points.sort_by_key(|(t, _)| *t);
+  Ok( points )
+}
+
+/// api_url is NetatmoConfig::api_url, i.e. "https://api.netatmo.com/api".
+async fn get<T : DeserializeOwned>(client : &reqwest::Client, api_url : &str, method : &str, query : &[(&str, String)], access_token : &str, timeout : &Option<Duration>) -> Result<T, ApiError> {
+  let request = client.get( format!("{}/{}", api_url.trim_end_matches('/'), method) ).query(query);
+  send(request, method, access_token, timeout).await
+}
+
+async fn post(client : &reqwest::Client, api_url : &str, method : &str, params : &[(&str, String)], access_token : &str, timeout : &Option<Duration>) -> Result<(), ApiError> {
+  let request = client.post( format!("{}/{}", api_url.trim_end_matches('/'), method) ).form(params);
+  let answer : StatusAnswer = send(request, method, access_token, timeout).await?;
+  if answer.status != "ok" {
+      return Err( ApiError::Answer( format!("{} failed with status {}", method, answer.status) ) );
+  }
+  Ok(())
+}
+
+pub fn requests_sent() -> u64 {
+  REQUESTS_SENT.load(Ordering::Relaxed)
+}
+
+async fn send<T : DeserializeOwned>(mut request : reqwest::RequestBuilder, method : &str, access_token : &str, timeout : &Option<Duration>) -> Result<T, ApiError> {
+  REQUESTS_SENT.fetch_add(1, Ordering::Relaxed);
+  request = request.bearer_auth(access_token);
+  if let Some( timeout ) = timeout {
+      request = request.timeout(*timeout);
+  }
+
+  let response = request.send().await.map_err(|e| ApiError::Network( format!("{} request failed: {}", method, e) ))?;
+  let status = response.status();
+  if !status.is_success() {
+      let body = response.text().await.unwrap_or_default();
+      // proxies and the like answer with something else than Netatmo error
+      let (code, message) = match serde_json::from_str::<ErrorAnswer>(&body) {
+        Ok( answer ) => (Some( answer.error.code ), answer.error.message),
+        Err( _ ) => (None, body),
+      };
+      return Err( ApiError::Status { method : method.to_string(), status, code, message } );
+  }
+
+  // connection may break while the body is received
+  let body = response.text().await.map_err(|e| ApiError::Network( format!("Failed to receive {} response: {}", method, e) ))?;
+  serde_json::from_str::<T>(&body).map_err(|e| ApiError::Answer( format!("Failed to parse {} response: {}", method, e) ))
+}
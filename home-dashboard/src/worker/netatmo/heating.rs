@@ -0,0 +1,92 @@
+//! Netatmo Energy: thermostat, valves and boiler relay of a home.
+use std::time::Duration;
+use crate::interface::{HeatingState, HeatingRoomState, HeatingConfig, ThermMode, HomeCommand, MIN_SETPOINT, MAX_SETPOINT};
+use super::api;
+
+// module types of homestatus
+const RELAY : &str = "NAPlug";
+const THERMOSTAT : &str = "NATherm1";
+
+pub async fn get_heating_state(client : &reqwest::Client, api_url : &str, access_token : &str, cfg : &HeatingConfig, timeout : &Option<Duration>) -> Result<HeatingState, String>
+{
+  let homes = api::get_homes_data(client, api_url, access_token, timeout).await?.body.homes;
+  let home = match &cfg.home_id {
+    Some( id ) => homes.into_iter().find(|h| &h.id == id),
+    None => homes.into_iter().find(|h| !h.rooms.is_empty()),
+  };
+  let Some( home ) = home else {
+    return Err( format!("Can't find netatmo home {:?} with rooms", cfg.home_id) );
+  };
+
+  let status = api::get_home_status(client, api_url, access_token, &home.id, timeout).await?.body.home;
+
+  let rooms = home.rooms.iter()
+    .filter_map(|room| {
+      // rooms without thermostat or valve have no status
+      let s = status.rooms.iter().find(|s| s.id == room.id)?;
+      Some( HeatingRoomState {
+        id : room.id.clone(),
+        name : room.name.clone(),
+        measured_temperature : s.therm_measured_temperature,
+        setpoint_temperature : s.therm_setpoint_temperature,
+        manual : s.therm_setpoint_mode.as_deref() == Some( "manual" ),
+        reachable : s.reachable.unwrap_or(true),
+      })
+    })
+    .collect();
+
+  let boiler_on = status.modules.iter()
+    .filter(|m| m.module_type == RELAY || m.module_type == THERMOSTAT)
+    .filter_map(|m| m.boiler_status)
+    .reduce(|a, b| a || b);
+
+  Ok( HeatingState {
+    home_id : home.id,
+    home_name : home.name,
+    mode : parse_therm_mode(home.therm_mode.as_deref()),
+    rooms,
+    boiler_on,
+  })
+}
+
+/// commands which are not about heating are ignored
+pub async fn execute_heating_command(
+  client : &reqwest::Client,
+  api_url : &str,
+  access_token : &str,
+  home_id : &str,
+  cmd : HomeCommand,
+  timeout : &Option<Duration>) -> Result<(), String>
+{
+  log::debug!("Got heating CMD: {:?}", cmd);
+  match cmd {
+    HomeCommand::SetRoomSetpoint( room_id, temperature ) => {
+      if !(MIN_SETPOINT..=MAX_SETPOINT).contains(&temperature) {
+          return Err( format!("Set-point {} of room {} is out of {}..={}", temperature, room_id, MIN_SETPOINT, MAX_SETPOINT) );
+      }
+      Ok( api::set_room_thermpoint(client, api_url, access_token, home_id, &room_id, temperature, timeout).await? )
+    },
+    HomeCommand::SetThermMode( mode ) =>
+      Ok( api::set_therm_mode(client, api_url, access_token, home_id, therm_mode_name(mode), timeout).await? ),
+    cmd => {
+      log::warn!("{:?} is not a heating command", cmd);
+      Ok(())
+    },
+  }
+}
+
+fn parse_therm_mode(mode : Option<&str>) -> ThermMode {
+  match mode {
+    Some( "away" ) => ThermMode::Away,
+    Some( "hg" ) => ThermMode::FrostGuard,
+    _ => ThermMode::Schedule,
+  }
+}
+
+fn therm_mode_name(mode : ThermMode) -> &'static str {
+  match mode {
+    ThermMode::Schedule => "schedule",
+    ThermMode::Away => "away",
+    ThermMode::FrostGuard => "hg",
+  }
+}
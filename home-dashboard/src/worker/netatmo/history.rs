@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use serde::{Serialize, Deserialize};
+use crate::interface::{Measure, MeasureHistory};
+use super::api::{self, MeasureQuery};
+
+const HISTORY_SCALE : &str = "30min";
+// the longest period shown by GUI
+const HISTORY_DEPTH : i64 = 7 * 24 * 3600;
+// new point appears once per scale, no reason to ask more often
+const HISTORY_UPDATE_INTERVAL : Duration = Duration::from_secs(30 * 60);
+const HISTORY_FILE_NAME : &str = "netatmo-history.json";
+
+/// device, or module of the device, whose measurements are shown in a tile
+#[derive(Debug, Clone)]
+pub struct HistorySource {
+  pub device_id : String,
+  pub module_id : Option<String>,
+  pub measures : Vec<Measure>,
+}
+
+type SeriesKey = (String, Option<String>, Measure);
+
+/// series as they are kept on disk, JSON has no tuple keys
+#[derive(Serialize, Deserialize)]
+struct StoredSeries {
+  device_id : String,
+  module_id : Option<String>,
+  measure : Measure,
+  points : Vec<(i64, f32)>,
+}
+
+/// Measurements received so far, so only new points are requested from Netatmo on update.
+/// They are kept next to the token file, so restart doesn't cost a week of history requests.
+#[derive(Default)]
+pub struct HistoryCache {
+  series : HashMap<SeriesKey, Vec<(i64, f32)>>,
+  updated_at : Option<Instant>,
+  // None keeps history in memory only
+  path : Option<PathBuf>,
+}
+
+impl HistoryCache {
+  /// history of previous run kept in dir, empty one if there is none or it can't be read
+  pub fn load(dir : &Path) -> HistoryCache {
+      HistoryCache::load_path( dir.join(HISTORY_FILE_NAME) )
+  }
+
+  fn load_path(path : PathBuf) -> HistoryCache {
+      let mut cache = HistoryCache { path : Some( path.clone() ), ..HistoryCache::default() };
+      if !path.exists() {
+          return cache;
+      }
+      let stored = fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| serde_json::from_str::<Vec<StoredSeries>>(&text).map_err(|e| e.to_string()));
+      match stored {
+        Ok( stored ) => {
+          cache.series = stored.into_iter()
+            .map(|s| ((s.device_id, s.module_id, s.measure), s.points))
+            .collect();
+        },
+        Err( e ) => log::warn!("Failed to load netatmo history from {}, it's requested again: {}", path.display(), e),
+      }
+      cache
+  }
+
+  /// failure only costs requests of the whole history on next start, so it's just logged
+  pub fn save(&self) {
+      let Some( path ) = &self.path else { return; };
+      let stored : Vec<StoredSeries> = self.series.iter()
+        .map(|((device_id, module_id, measure), points)| StoredSeries {
+          device_id : device_id.clone(),
+          module_id : module_id.clone(),
+          measure : *measure,
+          points : points.clone(),
+        })
+        .collect();
+
+      let res = serde_json::to_string(&stored)
+        .map_err(|e| e.to_string())
+        .and_then(|text| {
+          if let Some( dir ) = path.parent() {
+              fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+          }
+          fs::write(path, text).map_err(|e| e.to_string())
+        });
+      if let Err( e ) = res {
+          log::warn!("Failed to store netatmo history to {}: {}", path.display(), e);
+      }
+  }
+
+  pub fn is_due(&self) -> bool {
+      self.updated_at.map_or(true, |t| t.elapsed() >= HISTORY_UPDATE_INTERVAL)
+  }
+
+  pub async fn update(&mut self, client : &reqwest::Client, api_url : &str, access_token : &str, sources : &[&HistorySource], timeout : &Option<Duration>) -> Result<(), String> {
+      // failed update waits for the next interval as well, history is not worth the quota
+      self.updated_at = Some( Instant::now() );
+      let now = Utc::now().timestamp();
+
+      for source in sources {
+          let date_begin = self.last_time(source).map(|t| t + 1).unwrap_or(now - HISTORY_DEPTH);
+          let types : Vec<&str> = source.measures.iter().map(measure_type).collect();
+          let query = MeasureQuery {
+            device_id : &source.device_id,
+            module_id : source.module_id.as_deref(),
+            scale : HISTORY_SCALE,
+            types : &types,
+            date_begin,
+          };
+          let points = api::get_measure(client, api_url, access_token, &query, timeout).await?;
+
+          for (time, values) in points {
+              for (measure, value) in source.measures.iter().zip(values) {
+                  if let Some( value ) = value {
+                      self.series.entry( series_key(source, *measure) ).or_default().push( (time, value) );
+                  }
+              }
+          }
+      }
+
+      for series in self.series.values_mut() {
+          series.retain(|(t, _)| *t >= now - HISTORY_DEPTH);
+      }
+      Ok(())
+  }
+
+  /// everything known about the sources, they are expected to have different measures
+  pub fn history(&self, sources : &[HistorySource]) -> MeasureHistory {
+      let mut history = MeasureHistory::default();
+      for source in sources {
+          for measure in &source.measures {
+              if let Some( series ) = self.series.get( &series_key(source, *measure) ) {
+                  history.series.insert(*measure, series.clone());
+              }
+          }
+      }
+      history
+  }
+
+  fn last_time(&self, source : &HistorySource) -> Option<i64> {
+      source.measures.iter()
+        .filter_map(|m| self.series.get( &series_key(source, *m) ))
+        .filter_map(|s| s.last().map(|(t, _)| *t))
+        .max()
+  }
+}
+
+fn series_key(source : &HistorySource, measure : Measure) -> SeriesKey {
+  (source.device_id.clone(), source.module_id.clone(), measure)
+}
+
+fn measure_type(measure : &Measure) -> &'static str {
+  match measure {
+    Measure::Temperature => "temperature",
+    Measure::Humidity => "humidity",
+    Measure::CO2 => "co2",
+    Measure::Pressure => "pressure",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn history_survives_restart() {
+      let path = std::env::temp_dir().join( format!("netatmo-history-{}.json", std::process::id()) );
+      let source = HistorySource { device_id : String::from("70:ee:50:00:00:01"), module_id : None, measures : vec![Measure::CO2] };
+
+      let mut cache = HistoryCache::load_path(path.clone());
+      cache.series.insert(series_key(&source, Measure::CO2), vec![ (1000, 650.0), (2800, 700.0) ]);
+      cache.save();
+
+      let cache = HistoryCache::load_path(path.clone());
+      fs::remove_file(&path).unwrap();
+      assert_eq!(cache.history( std::slice::from_ref(&source) ).series[&Measure::CO2], vec![ (1000, 650.0), (2800, 700.0) ]);
+      assert_eq!(cache.last_time(&source), Some( 2800 ));
+      assert!(cache.is_due());
+  }
+
+  #[test]
+  fn broken_history_file_is_ignored() {
+      let path = std::env::temp_dir().join( format!("netatmo-history-broken-{}.json", std::process::id()) );
+      fs::write(&path, "[{\"device_id\":").unwrap();
+
+      let cache = HistoryCache::load_path(path.clone());
+      fs::remove_file(&path).unwrap();
+      assert!(cache.series.is_empty());
+  }
+}
@@ -0,0 +1,260 @@
+//! Imitation of Netatmo API for development without api.netatmo.com and for tests of watch_netatmo_loop.
+//! Answers are fixtures of resources/netatmo-mock with times replaced by the current one, getmeasure points are generated
+//! around the values of getmeasure.json, authorization is approved right away. It's also built as netatmo-mock binary,
+//! so nothing else of the crate is used here.
+//! It lives in home-dashboard rather than in netatmo-connect: the latter is a submodule of its own repository
+//! with api.netatmo.com addresses built in, so the dashboard is pointed here by NetatmoConfig::api_url and auth_url.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::{json, Value};
+use chrono::Utc;
+
+const STATIONS_DATA : &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/netatmo-mock/getstationsdata.json"));
+const HOMECOACHS_DATA : &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/netatmo-mock/gethomecoachsdata.json"));
+const HOMES_DATA : &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/netatmo-mock/homesdata.json"));
+const HOME_STATUS : &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/netatmo-mock/homestatus.json"));
+const MEASURE_VALUES : &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/netatmo-mock/getmeasure.json"));
+const TOKEN : &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/netatmo-mock/token.json"));
+// any code is accepted by token endpoint
+const AUTHORIZATION_CODE : &str = "mock-authorization-code";
+
+// fixture fields which are set to the current unix time
+const TIME_FIELDS : [&str; 3] = ["time_utc", "time_server", "last_seen"];
+// getmeasure answers with 30 minutes scale for the last week whatever is asked
+const MEASURE_SCALE : i64 = 30 * 60;
+const MEASURE_DEPTH : i64 = 7 * 24 * 3600;
+
+/// method name for MockNetatmo::fail which fails all of them
+pub const ALL_METHODS : &str = "*";
+
+/// error answers the way Netatmo gives them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+  // 403 with error code 2
+  InvalidToken,
+  // 403 with error code 3
+  TokenExpired,
+  // 403 with error code 13, i.e. homesdata without thermostat scopes
+  NoScope,
+  // 403 with error code 26, quota of the user is exceeded
+  UsageReached,
+  // 503 without JSON body, from the proxy in front of Netatmo
+  Unavailable,
+  // 200 with truncated body
+  InvalidJson,
+}
+
+impl Failure {
+  pub const NAMES : [&'static str; 6] = ["invalid-token", "token-expired", "no-scope", "usage-reached", "unavailable", "invalid-json"];
+
+  pub fn parse(name : &str) -> Option<Failure> {
+      match name {
+        "invalid-token" => Some( Failure::InvalidToken ),
+        "token-expired" => Some( Failure::TokenExpired ),
+        "no-scope" => Some( Failure::NoScope ),
+        "usage-reached" => Some( Failure::UsageReached ),
+        "unavailable" => Some( Failure::Unavailable ),
+        "invalid-json" => Some( Failure::InvalidJson ),
+        _ => None,
+      }
+  }
+}
+
+#[derive(Default)]
+struct MockState {
+  // by method name, i.e. "getstationsdata", or ALL_METHODS
+  failures : HashMap<String, Failure>,
+  // method names in order of requests
+  requests : Vec<String>,
+}
+
+/// Server serving API methods at api_url and OAuth endpoints at auth_url, it works until the runtime is stopped.
+#[derive(Clone)]
+pub struct MockNetatmo {
+  addr : SocketAddr,
+  state : Arc<Mutex<MockState>>,
+}
+
+impl MockNetatmo {
+  /// port 0 of the address picks a free one
+  pub fn start(addr : SocketAddr) -> Result<MockNetatmo, String> {
+      let state = Arc::new( Mutex::new( MockState::default() ) );
+      let service_state = state.clone();
+      let make_service = make_service_fn(move |_| {
+          let state = service_state.clone();
+          async move { Ok::<_, Infallible>( service_fn(move |request| answer(state.clone(), request)) ) }
+      });
+
+      let server = Server::try_bind(&addr).map_err(|e| format!("Failed to bind mock netatmo to {}: {}", addr, e))?.serve(make_service);
+      let addr = server.local_addr();
+      tokio::spawn(async move {
+          if let Err( e ) = server.await {
+              log::warn!("Mock netatmo server failed: {}", e);
+          }
+      });
+      Ok( MockNetatmo { addr, state } )
+  }
+
+  /// NetatmoConfig::api_url
+  pub fn api_url(&self) -> String {
+      format!("http://{}/api", self.addr)
+  }
+
+  /// NetatmoConfig::auth_url
+  pub fn auth_url(&self) -> String {
+      format!("http://{}/oauth2", self.addr)
+  }
+
+  /// method is the last part of the path, i.e. "getmeasure", "authorize" or "token"; None failure makes it answer normally again
+  pub fn fail(&self, method : &str, failure : Option<Failure>) {
+      let mut state = self.state.lock().unwrap();
+      match failure {
+        Some( failure ) => { state.failures.insert(method.to_string(), failure); },
+        None => { state.failures.remove(method); },
+      }
+  }
+
+  pub fn requests(&self) -> Vec<String> {
+      self.state.lock().unwrap().requests.clone()
+  }
+}
+
+async fn answer(state : Arc<Mutex<MockState>>, request : Request<Body>) -> Result<Response<Body>, Infallible> {
+  log::debug!("Mock netatmo got {} {}", request.method(), request.uri());
+  let path = request.uri().path().trim_end_matches('/').to_string();
+  let method = path.rsplit('/').next().unwrap_or_default().to_string();
+
+  let failure = {
+      let mut state = state.lock().unwrap();
+      state.requests.push(method.clone());
+      state.failures.get(&method).or( state.failures.get(ALL_METHODS) ).copied()
+  };
+  if let Some( failure ) = failure {
+      return Ok( failure_response(failure) );
+  }
+
+  if path == "/oauth2/authorize" {
+      return Ok( authorize(request.uri().query().unwrap_or_default()) );
+  }
+
+  let now = Utc::now().timestamp();
+  let body = match path.as_str() {
+    "/oauth2/token" => Some( TOKEN.to_string() ),
+    "/api/getstationsdata" => Some( fixture(STATIONS_DATA, now) ),
+    "/api/gethomecoachsdata" => Some( fixture(HOMECOACHS_DATA, now) ),
+    "/api/homesdata" => Some( fixture(HOMES_DATA, now) ),
+    "/api/homestatus" => Some( fixture(HOME_STATUS, now) ),
+    "/api/getmeasure" => Some( measure(request.uri().query().unwrap_or_default(), now) ),
+    "/api/setroomthermpoint" | "/api/setthermmode" => Some( json!({ "status" : "ok", "time_server" : now }).to_string() ),
+    _ => None,
+  };
+
+  Ok( match body {
+    Some( body ) => response(StatusCode::OK, body),
+    None => netatmo_error(StatusCode::NOT_FOUND, 21, "Invalid method"),
+  })
+}
+
+/// approval of the user, redirect to redirect_uri with the code and the state of the request
+fn authorize(query : &str) -> Response<Body> {
+  // only for decoding of the query
+  let request_url = reqwest::Url::parse( &format!("http://mock/?{}", query) ).expect("Query is not a part of URL");
+  let params : HashMap<String, String> = request_url.query_pairs().into_owned().collect();
+  let Some( redirect_uri ) = params.get("redirect_uri") else {
+      return netatmo_error(StatusCode::BAD_REQUEST, 1, "Missing redirect_uri");
+  };
+  let mut location = match reqwest::Url::parse(redirect_uri) {
+    Ok( l ) => l,
+    Err( _ ) => return netatmo_error(StatusCode::BAD_REQUEST, 1, "Invalid redirect_uri"),
+  };
+  location.query_pairs_mut().append_pair("code", AUTHORIZATION_CODE);
+  if let Some( state ) = params.get("state") {
+      location.query_pairs_mut().append_pair("state", state);
+  }
+
+  Response::builder()
+    .status(StatusCode::FOUND)
+    .header(LOCATION, location.as_str())
+    .body( Body::empty() )
+    .unwrap()
+}
+
+fn failure_response(failure : Failure) -> Response<Body> {
+  match failure {
+    Failure::InvalidToken => netatmo_error(StatusCode::FORBIDDEN, 2, "Invalid access_token"),
+    Failure::TokenExpired => netatmo_error(StatusCode::FORBIDDEN, 3, "Access token expired"),
+    Failure::NoScope => netatmo_error(StatusCode::FORBIDDEN, 13, "Application does not have the good scope rights"),
+    Failure::UsageReached => netatmo_error(StatusCode::FORBIDDEN, 26, "User usage reached"),
+    Failure::Unavailable => Response::builder()
+      .status(StatusCode::SERVICE_UNAVAILABLE)
+      .header(CONTENT_TYPE, "text/html")
+      .body( Body::from("<html><body><h1>503 Service Unavailable</h1></body></html>") )
+      .unwrap(),
+    Failure::InvalidJson => response(StatusCode::OK, String::from("{\"body\":{\"devices\":[{\"_id\":")),
+  }
+}
+
+fn netatmo_error(status : StatusCode, code : i64, message : &str) -> Response<Body> {
+  response(status, json!({ "error" : { "code" : code, "message" : message } }).to_string())
+}
+
+fn response(status : StatusCode, body : String) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .body( Body::from(body) )
+    .unwrap()
+}
+
+fn fixture(text : &str, now : i64) -> String {
+  let mut value : Value = serde_json::from_str(text).expect("Mock netatmo fixture is not JSON");
+  set_times(&mut value, now);
+  value.to_string()
+}
+
+fn set_times(value : &mut Value, now : i64) {
+  match value {
+    Value::Object( map ) => {
+      for (key, v) in map.iter_mut() {
+          if TIME_FIELDS.contains(&key.as_str()) {
+              *v = json!(now);
+          } else {
+              set_times(v, now);
+          }
+      }
+    },
+    Value::Array( values ) => values.iter_mut().for_each(|v| set_times(v, now)),
+    _ => (),
+  }
+}
+
+/// optimize=false answer: unix time -> values in order of requested types
+fn measure(query : &str, now : i64) -> String {
+  let base : HashMap<String, f64> = serde_json::from_str(MEASURE_VALUES).expect("Mock netatmo getmeasure.json is not a map of values");
+  let param = |name : &str| query.split('&')
+    .filter_map(|p| p.split_once('='))
+    .find(|(k, _)| *k == name)
+    // only commas between types are encoded in requests of the dashboard
+    .map(|(_, v)| v.replace("%2C", ",").replace("%2c", ","));
+
+  let types = param("type").unwrap_or_default();
+  let date_begin = param("date_begin").and_then(|v| v.parse::<i64>().ok()).unwrap_or(0).max(now - MEASURE_DEPTH);
+
+  let mut body = serde_json::Map::new();
+  let mut time = date_begin + (MEASURE_SCALE - date_begin.rem_euclid(MEASURE_SCALE)) % MEASURE_SCALE;
+  while time <= now {
+      // daily wave, to have something to look at in charts
+      let wave = (time as f64 * std::f64::consts::TAU / (24.0 * 3600.0)).sin();
+      let values : Vec<Value> = types.split(',')
+        .map(|t| base.get(t).map_or(Value::Null, |b| json!(b + b.abs() * 0.05 * wave)))
+        .collect();
+      body.insert(time.to_string(), Value::Array(values));
+      time += MEASURE_SCALE;
+  }
+  json!({ "body" : body, "status" : "ok", "time_server" : now }).to_string()
+}
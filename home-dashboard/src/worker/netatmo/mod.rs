@@ -0,0 +1,715 @@
+use reqwest;
+use netatmo_connect::*;
+use crate::interface::{HomeCommand, HeatingState, LocationState, WeatherData, OutdoorWeatherData, AirQualityData, RainData, WindData, Trend, NetatmoConfig, RoomConfig, RoomSource, RoomModuleType, NetatmoErrorKind, NetatmoErrorState, Measure, ReadingStatus};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{Sender, Receiver};
+use tokio::sync::mpsc::error::TrySendError;
+use chrono::Utc;
+use std::option::Option;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use rand::Rng;
+
+mod api;
+mod token;
+mod history;
+mod heating;
+mod schedule;
+// parts of it are used by netatmo-mock binary only
+#[cfg(test)]
+#[allow(dead_code)]
+mod mock;
+use token::*;
+pub use token::data_dir;
+use history::*;
+use heating::*;
+use schedule::*;
+
+const MIN_BACKOFF : Duration = Duration::from_secs(10);
+const MAX_BACKOFF : Duration = Duration::from_secs(30 * 60);
+// Netatmo limits requests per hour, no reason to knock earlier
+const RATE_LIMIT_BACKOFF : Duration = Duration::from_secs(10 * 60);
+// seconds, staleness of readings is judged by local clock
+const MAX_CLOCK_SKEW : i64 = 300;
+const ROOM_MEASURES : [Measure; 3] = [Measure::Temperature, Measure::Humidity, Measure::CO2];
+
+/// failed poll with kind decided where the error comes from
+#[derive(Debug)]
+struct PollError {
+    kind : NetatmoErrorKind,
+    message : String,
+}
+
+impl From<api::ApiError> for PollError {
+    fn from(e : api::ApiError) -> Self {
+        PollError { kind : e.kind(), message : e.to_string() }
+    }
+}
+
+/// netatmo_connect reports errors as strings
+impl From<String> for PollError {
+    fn from(e : String) -> Self {
+        PollError { kind : classify_connect_error(&e), message : e }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct NetatmoData {
+    // in order of NetatmoConfig::locations
+    pub locations : Vec<LocationState>,
+    pub heating : Option<HeatingState>,
+    pub error : Option<NetatmoErrorState>,
+}
+
+impl NetatmoData {
+    /// unreachable modules have no dashboard_data at all, so their last known readings are kept
+    fn keep_last_known(&mut self, previous : &NetatmoData) {
+        for location in &mut self.locations {
+            let Some( previous ) = previous.locations.iter().find(|l| l.title == location.title) else { continue; };
+            if let (Some( wd ), Some( prev )) = (location.weather_data.as_mut(), previous.weather_data.as_ref()) {
+                if wd.outdoor_weather.is_none() {
+                    wd.outdoor_weather = prev.outdoor_weather.clone().map(|mut d| { d.status.reachable = false; d });
+                }
+                if wd.rain.is_none() {
+                    wd.rain = prev.rain.clone().map(|mut d| { d.status.reachable = false; d });
+                }
+                if wd.wind.is_none() {
+                    wd.wind = prev.wind.clone().map(|mut d| { d.status.reachable = false; d });
+                }
+            }
+            for (title, prev) in &previous.room_data {
+                location.room_data.entry(title.clone()).or_insert_with(|| {
+                    let mut d = prev.clone();
+                    d.status.reachable = false;
+                    d
+                });
+            }
+        }
+    }
+
+    /// unix times of the last readings of reachable devices, they upload the next ones in sync with them
+    fn measured_at(&self) -> impl Iterator<Item = i64> + '_ {
+        self.locations.iter()
+          .flat_map(|location| {
+              let station = location.weather_data.iter().flat_map(|wd| {
+                  std::iter::once(&wd.status)
+                    .chain( wd.outdoor_weather.iter().map(|d| &d.status) )
+                    .chain( wd.rain.iter().map(|d| &d.status) )
+                    .chain( wd.wind.iter().map(|d| &d.status) )
+              });
+              station.chain( location.room_data.values().map(|d| &d.status) )
+          })
+          .filter(|s| s.reachable)
+          .filter_map(|s| s.measured_at)
+    }
+}
+
+/// token and history are kept in data_dir, or in memory only if it's None
+pub async fn watch_netatmo_loop(
+    netatmo_sender : Sender<NetatmoData> ,
+    mut command_receiver : Receiver<HomeCommand>,
+    cfg : ConnectConfig,
+    netatmo_cfg : NetatmoConfig,
+    data_dir : Option<PathBuf>) -> Result<(), String>
+{
+  let mut token = match data_dir.as_deref().map(load_token) {
+    Some( Err( e ) ) => {
+      log::warn!("Failed to load netatmo token, authorization is needed: {}", e);
+      None
+    },
+    Some( Ok( t ) ) => t,
+    None => None,
+  };
+  let mut history = data_dir.as_deref().map(HistoryCache::load).unwrap_or_default();
+  let has_home_coachs = netatmo_cfg.locations.iter().flat_map(|l| &l.rooms).any(|r| r.module_type == RoomModuleType::HomeCoach);
+
+  let client = reqwest::Client::new();
+  let timeout = Some( Duration::from_secs(1) );
+
+  // last received data, resent with error while requests fail
+  let mut netatmo_data = NetatmoData::default();
+  let mut failures : u32 = 0;
+  let min_poll_interval = Duration::from_secs(netatmo_cfg.min_poll_interval);
+  let mut budget = RequestBudget::new(netatmo_cfg.requests_per_hour);
+  // requests of heating commands are made between polls, so everything since the previous record is counted
+  let mut requests_recorded = api::requests_sent();
+
+  loop {
+    let res = async {
+        if token.is_none() {
+            let t = new_token(&client, &cfg, netatmo_cfg.auth_url.as_deref(), &timeout).await?;
+            save_token(data_dir.as_deref(), &t);
+            token = Some( t );
+        }
+        let token = token.as_mut().ok_or( PollError { kind : NetatmoErrorKind::Auth, message : String::from("There is no access token") } )?;
+        if token.expires_at < Instant::now() {
+            log::info!("Access token is expired!");
+            *token = refreshed_token(&client, &cfg, netatmo_cfg.auth_url.as_deref(), token, &timeout).await?;
+            save_token(data_dir.as_deref(), token);
+        }
+
+        let res = api::get_stations_data(&client, &netatmo_cfg.api_url, &token.access_token, &timeout).await?;
+
+        let clock_skew = Utc::now().timestamp() - res.time_server;
+        if clock_skew.abs() > MAX_CLOCK_SKEW {
+            log::warn!("Local clock differs from netatmo server one by {} seconds", clock_skew);
+        }
+
+        let mut data = NetatmoData::default();
+
+        let home_coachs = if has_home_coachs {
+            api::get_homecoachs_data(&client, &netatmo_cfg.api_url, &token.access_token, &timeout).await?.body.devices
+        } else {
+            Vec::new()
+        };
+
+        // outdoor and room sources of every location
+        let mut location_sources = Vec::new();
+        for location_cfg in &netatmo_cfg.locations {
+            let mut location = LocationState { title : location_cfg.title.clone(), ..Default::default() };
+            let station = res.body.devices.iter().find(|d| location_cfg.station.matches(&d.id, &d.station_name));
+            if station.is_none() {
+                log::warn!("Can't find weather station {:?} of {}", location_cfg.station, location_cfg.title);
+            }
+            location.weather_data = station.map(parse_weather_data);
+
+            let outdoor_sources = station.map(outdoor_history_sources).unwrap_or_default();
+            let mut room_sources = HashMap::new();
+            room_readings(&location_cfg.rooms, &res.body.devices, station, &home_coachs, &mut location.room_data, &mut room_sources);
+
+            data.locations.push(location);
+            location_sources.push( (outdoor_sources, room_sources) );
+        }
+
+        // heating needs its own scopes and fails apart from weather, so the last known state is kept like history,
+        // and its errors never drop the token
+        if let Some( heating_cfg ) = &netatmo_cfg.heating {
+            data.heating = match get_heating_state(&client, &netatmo_cfg.api_url, &token.access_token, heating_cfg, &timeout).await {
+              Ok( heating ) => Some( heating ),
+              Err( e ) => {
+                log::warn!("Failed to get netatmo heating: {}", e);
+                netatmo_data.heating.clone()
+              },
+            };
+        }
+
+        if history.is_due() {
+            let sources : Vec<&HistorySource> = location_sources.iter()
+              .flat_map(|(outdoor_sources, room_sources)| outdoor_sources.iter().chain( room_sources.values() ))
+              .collect();
+            if let Err( e ) = history.update(&client, &netatmo_cfg.api_url, &token.access_token, &sources, &timeout).await {
+                log::warn!("Failed to update netatmo history: {}", e);
+            }
+            // points received before the failure are worth keeping as well
+            history.save();
+        }
+        for (location, (outdoor_sources, room_sources)) in data.locations.iter_mut().zip(&location_sources) {
+            location.outdoor_history = history.history(outdoor_sources);
+            for (title, source) in room_sources {
+                location.room_history.insert(title.clone(), history.history( std::slice::from_ref(source) ));
+            }
+        }
+
+        Ok::<NetatmoData, PollError>( data )
+    }.await;
+    let requests_sent = api::requests_sent();
+    budget.record( requests_sent - requests_recorded );
+    requests_recorded = requests_sent;
+
+    let delay = match res {
+      Ok( mut data ) => {
+        failures = 0;
+        data.keep_last_known(&netatmo_data);
+        netatmo_data = data;
+        next_poll(netatmo_data.measured_at(), min_poll_interval)
+      },
+      Err( PollError { kind, message } ) => {
+        failures += 1;
+        log::warn!("Failed to get netatmo data ({:?}, attempt {}): {}", kind, failures, message);
+        if kind == NetatmoErrorKind::Auth {
+            token = match token {
+              // access token is rejected before its expiration, try to refresh it first
+              Some( mut t ) if t.expires_at >= Instant::now() => {
+                t.expires_at = Instant::now();
+                Some( t )
+              },
+              // refresh token is rejected, the only way is to authorize again
+              _ => None,
+            };
+        }
+        if kind == NetatmoErrorKind::RateLimit {
+            budget.exhausted();
+        }
+        let delay = retry_delay(&kind, failures);
+        netatmo_data.error = Some( NetatmoErrorState { kind, message, attempt : failures } );
+        delay
+      },
+    };
+    let delay = delay.max( budget.delay() );
+
+    match netatmo_sender.try_send(netatmo_data.clone()) {
+      Ok(()) => (),
+      Err( TrySendError::Full( _ ) ) => log::warn!("Failed to send weather data, update_state_loop is not consuming it!"),
+      Err( TrySendError::Closed( _ ) ) => {
+        log::warn!("Failed to send weather data - channel is closed. Probably update_state_loop is dead now. Exiting....");
+        return Ok(());
+      },
+   }
+
+     tokio::select! {
+       _ = tokio::time::sleep(delay) => (),
+       // poll right after the command to show its result
+       Some( cmd ) = command_receiver.recv() => match cmd {
+         HomeCommand::RefreshNetatmo => (),
+         cmd => {
+           let home_id = netatmo_data.heating.as_ref().map(|h| h.home_id.clone());
+           match (token.as_ref(), home_id) {
+             (Some( token ), Some( home_id )) => {
+               if let Err( e ) = execute_heating_command(&client, &netatmo_cfg.api_url, &token.access_token, &home_id, cmd, &timeout).await {
+                   log::warn!("Failed to execute heating command: {}", e);
+               }
+             },
+             _ => log::warn!("Netatmo heating is not received yet, ignoring {:?}", cmd),
+           }
+         },
+       },
+     }
+   };
+}
+
+/// exponential, with jitter to not hit Netatmo in sync with other clients after its outage
+fn retry_delay(kind : &NetatmoErrorKind, attempt : u32) -> Duration {
+  let min_backoff = if *kind == NetatmoErrorKind::RateLimit { RATE_LIMIT_BACKOFF } else { MIN_BACKOFF };
+  let backoff = min_backoff.saturating_mul( 2u32.saturating_pow( attempt.saturating_sub(1) ) ).min(MAX_BACKOFF);
+  backoff.mul_f64( rand::thread_rng().gen_range(1.0..1.25) )
+}
+
+/// netatmo_connect reports errors as strings, so kind is guessed by the text of HTTP status and OAuth error in it
+fn classify_connect_error(e : &str) -> NetatmoErrorKind {
+  let text = e.to_lowercase();
+  let has_status = |status : reqwest::StatusCode| text.contains( &status.to_string().to_lowercase() );
+
+  if has_status( reqwest::StatusCode::TOO_MANY_REQUESTS ) || text.contains("usage reached") {
+      return NetatmoErrorKind::RateLimit;
+  }
+  if has_status( reqwest::StatusCode::UNAUTHORIZED ) || has_status( reqwest::StatusCode::FORBIDDEN )
+    || text.contains("invalid_grant") || text.contains("invalid_token") {
+      return NetatmoErrorKind::Auth;
+  }
+  let server_statuses = [
+    reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+    reqwest::StatusCode::BAD_GATEWAY,
+    reqwest::StatusCode::SERVICE_UNAVAILABLE,
+    reqwest::StatusCode::GATEWAY_TIMEOUT,
+  ];
+  if server_statuses.into_iter().any(has_status) {
+      return NetatmoErrorKind::Server;
+  }
+  NetatmoErrorKind::Network
+}
+
+fn parse_weather_data(device : &api::Station) -> WeatherData
+{
+    let mut weather_data =  WeatherData { status : station_status(device), ..WeatherData::default() };
+
+    if let Some( d ) = &device.dashboard_data {
+        weather_data.room_temperature = d.temperature.unwrap_or_default();
+        weather_data.room_humidity = d.humidity.unwrap_or_default();
+        weather_data.room_co2 = d.co2.unwrap_or_default();
+        weather_data.room_noise = d.noise.unwrap_or_default();
+        weather_data.pressure = d.pressure.unwrap_or_default();
+        weather_data.pressure_trend = d.pressure_trend.as_deref().and_then(parse_trend);
+    }
+
+    for module in &device.modules {
+        // unreachable module has no data
+        let Some( d ) = &module.dashboard_data else { continue; };
+        match module.module_type.as_str() {
+            api::OUTDOOR_MODULE => weather_data.outdoor_weather = Some( OutdoorWeatherData {
+                temperature : d.temperature.unwrap_or_default(),
+                temperature_trend : d.temp_trend.as_deref().and_then(parse_trend),
+                humidity : d.humidity.unwrap_or_default(),
+                status : module_status(module),
+            }),
+            api::RAIN_GAUGE => weather_data.rain = Some( RainData {
+                last_hour : d.sum_rain_1.unwrap_or_default(),
+                last_day : d.sum_rain_24.unwrap_or_default(),
+                status : module_status(module),
+            }),
+            api::WIND_GAUGE => weather_data.wind = Some( WindData {
+                strength : d.wind_strength.unwrap_or_default(),
+                angle : d.wind_angle.unwrap_or_default(),
+                gust_strength : d.gust_strength.unwrap_or_default(),
+                status : module_status(module),
+            }),
+            // shown as rooms, see room_readings
+            api::INDOOR_MODULE => (),
+            t => log::debug!("Unknown module type {} of {}", t, module.module_name),
+        }
+    }
+
+    if weather_data.outdoor_weather.is_none() {
+        log::warn!("Can't find outdoor module within device");
+    }
+
+    weather_data
+}
+
+/// rooms of weather station modules and home coachs
+/// own_station is the one of the location, stations of other locations are searched only by id or name
+fn room_readings(
+    rooms : &[RoomConfig],
+    stations : &[api::Station],
+    own_station : Option<&api::Station>,
+    home_coachs : &[api::Station],
+    data : &mut HashMap<String, AirQualityData>,
+    sources : &mut HashMap<String, HistorySource>)
+{
+    let own_station = own_station.map(std::slice::from_ref).unwrap_or_default();
+    for room in rooms {
+        let stations = if room.source == RoomSource::First { own_station } else { stations };
+        let found = match room.module_type {
+            RoomModuleType::WeatherStation => stations.iter()
+                .find(|d| room.source.matches(&d.id, &d.station_name))
+                .map(|d| (d.id.clone(), None, d.dashboard_data.as_ref(), station_status(d))),
+            RoomModuleType::IndoorModule => stations.iter()
+                .flat_map(|d| d.modules.iter().map(move |m| (d, m)))
+                .filter(|(_, m)| m.module_type == api::INDOOR_MODULE)
+                .find(|(_, m)| room.source.matches(&m.id, &m.module_name))
+                .map(|(d, m)| (d.id.clone(), Some( m.id.clone() ), m.dashboard_data.as_ref(), module_status(m))),
+            RoomModuleType::HomeCoach => home_coachs.iter()
+                .find(|d| room.source.matches(&d.id, &d.station_name))
+                .map(|d| (d.id.clone(), None, d.dashboard_data.as_ref(), station_status(d))),
+        };
+
+        let Some( (device_id, module_id, dashboard_data, status) ) = found else {
+            log::warn!("Can't find {:?} {:?} for room {}", room.module_type, room.source, room.title);
+            continue;
+        };
+
+        // history is there even if module is unreachable right now
+        sources.insert(room.title.clone(), HistorySource { device_id, module_id, measures : ROOM_MEASURES.to_vec() });
+
+        match dashboard_data {
+            None => log::warn!("There is no data for room {}, module is unreachable", room.title),
+            Some( d ) => {
+                data.insert(room.title.clone(), AirQualityData {
+                    room_temperature : d.temperature.unwrap_or_default(),
+                    room_humidity : d.humidity.unwrap_or_default(),
+                    room_co2 : d.co2.unwrap_or_default(),
+                    room_noise : d.noise,
+                    status,
+                });
+            },
+        }
+    }
+}
+
+fn station_status(device : &api::Station) -> ReadingStatus
+{
+    ReadingStatus {
+        measured_at : device.dashboard_data.as_ref().and_then(|d| d.time_utc),
+        reachable : device.reachable.unwrap_or(true),
+    }
+}
+
+fn module_status(module : &api::Module) -> ReadingStatus
+{
+    ReadingStatus {
+        measured_at : module.dashboard_data.as_ref().and_then(|d| d.time_utc).or(module.last_seen),
+        reachable : module.reachable.unwrap_or(true),
+    }
+}
+
+/// outdoor module has no barometer, pressure is measured by the main one
+fn outdoor_history_sources(device : &api::Station) -> Vec<HistorySource>
+{
+    let mut sources = vec![ HistorySource { device_id : device.id.clone(), module_id : None, measures : vec![Measure::Pressure] } ];
+    if let Some( module ) = device.modules.iter().find(|m| m.module_type == api::OUTDOOR_MODULE) {
+        sources.push( HistorySource {
+            device_id : device.id.clone(),
+            module_id : Some( module.id.clone() ),
+            measures : vec![Measure::Temperature, Measure::Humidity],
+        });
+    }
+    sources
+}
+
+/// failure only costs authorization on next start, so it's not a reason to stop
+fn save_token(data_dir : Option<&Path>, token : &Token) {
+  let Some( dir ) = data_dir else { return; };
+  if let Err( e ) = store_token(dir, token) {
+    log::warn!("Failed to store netatmo token: {}", e);
+  }
+}
+
+fn parse_trend(str : &str) -> Option<Trend>
+{
+  if str == "up" { return Some(Trend::Up); }
+  if str == "down" { return Some(Trend::Down); }
+  if str == "stable" { return Some(Trend::Stable); }
+
+  log::error!("Unknown string for describing Trend: {}", str);
+  None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use tokio::sync::mpsc::channel;
+    use mock::{MockNetatmo, Failure, ALL_METHODS};
+    use crate::interface::{HeatingConfig, LocationConfig, MAX_SETPOINT};
+
+    const RECEIVE_TIMEOUT : Duration = Duration::from_secs(5);
+
+    fn start_mock() -> MockNetatmo {
+        MockNetatmo::start( "127.0.0.1:0".parse().unwrap() ).unwrap()
+    }
+
+    /// empty directory for token and history of one test, removed with it
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name : &str) -> TestDir {
+            let dir = std::env::temp_dir().join( format!("netatmo-{}-{}", name, std::process::id()) );
+            let _ = std::fs::remove_dir_all(&dir);
+            TestDir( dir )
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// loop with valid token of the mock, RefreshNetatmo makes it poll again right away
+    fn start_loop(mock : &MockNetatmo, netatmo_cfg : NetatmoConfig, dir : &TestDir) -> (Receiver<NetatmoData>, Sender<HomeCommand>) {
+        let token = Token {
+            access_token : String::from("mock-access-token"),
+            refresh_token : String::from("mock-refresh-token"),
+            expires_at : Instant::now() + Duration::from_secs(3600),
+        };
+        store_token(&dir.0, &token).unwrap();
+        start_loop_without_token(mock, netatmo_cfg, dir)
+    }
+
+    fn start_loop_without_token(mock : &MockNetatmo, netatmo_cfg : NetatmoConfig, dir : &TestDir) -> (Receiver<NetatmoData>, Sender<HomeCommand>) {
+        let (netatmo_sender, netatmo_receiver) = channel(10);
+        let (command_sender, command_receiver) = channel(10);
+        let netatmo_cfg = NetatmoConfig { api_url : mock.api_url(), auth_url : Some( mock.auth_url() ), ..netatmo_cfg };
+        tokio::spawn( watch_netatmo_loop(netatmo_sender, command_receiver, ConnectConfig::default(), netatmo_cfg, Some( dir.0.clone() )) );
+        (netatmo_receiver, command_sender)
+    }
+
+    async fn receive(receiver : &mut Receiver<NetatmoData>) -> NetatmoData {
+        tokio::time::timeout(RECEIVE_TIMEOUT, receiver.recv()).await
+          .expect("watch_netatmo_loop sent nothing")
+          .expect("watch_netatmo_loop is gone")
+    }
+
+    async fn poll_again(receiver : &mut Receiver<NetatmoData>, command_sender : &Sender<HomeCommand>) -> NetatmoData {
+        command_sender.send(HomeCommand::RefreshNetatmo).await.unwrap();
+        receive(receiver).await
+    }
+
+    fn status_error(status : StatusCode, code : Option<i64>) -> api::ApiError {
+        api::ApiError::Status { method : String::from("getstationsdata"), status, code, message : String::new() }
+    }
+
+    #[test]
+    fn api_error_is_classified_by_status_and_code() {
+        assert_eq!(status_error(StatusCode::TOO_MANY_REQUESTS, None).kind(), NetatmoErrorKind::RateLimit);
+        assert_eq!(status_error(StatusCode::FORBIDDEN, Some( 26 )).kind(), NetatmoErrorKind::RateLimit);
+        assert_eq!(status_error(StatusCode::FORBIDDEN, Some( 3 )).kind(), NetatmoErrorKind::Auth);
+        assert_eq!(status_error(StatusCode::UNAUTHORIZED, None).kind(), NetatmoErrorKind::Auth);
+        assert_eq!(status_error(StatusCode::FORBIDDEN, None).kind(), NetatmoErrorKind::Server);
+        assert_eq!(status_error(StatusCode::FORBIDDEN, Some( 13 )).kind(), NetatmoErrorKind::Server);
+        assert_eq!(status_error(StatusCode::BAD_GATEWAY, None).kind(), NetatmoErrorKind::Server);
+        assert_eq!(status_error(StatusCode::NOT_FOUND, None).kind(), NetatmoErrorKind::Server);
+        assert_eq!(api::ApiError::Network( String::from("getstationsdata request failed: timeout") ).kind(), NetatmoErrorKind::Network);
+    }
+
+    #[test]
+    fn broken_answer_is_not_auth_error() {
+        let e = api::ApiError::Answer( String::from("Failed to parse getstationsdata response: expected value at line 1 column 403") );
+        assert_eq!(e.kind(), NetatmoErrorKind::Server);
+    }
+
+    #[test]
+    fn connect_error_is_classified_by_text() {
+        assert_eq!(classify_connect_error("Token request failed with 400 Bad Request: {\"error\":\"invalid_grant\"}"), NetatmoErrorKind::Auth);
+        assert_eq!(classify_connect_error("Token request failed with 503 Service Unavailable"), NetatmoErrorKind::Server);
+        assert_eq!(classify_connect_error("Token request failed with 429 Too Many Requests"), NetatmoErrorKind::RateLimit);
+        // numbers which are not HTTP status
+        assert_eq!(classify_connect_error("error decoding response body: line 1 column 403"), NetatmoErrorKind::Network);
+    }
+
+    #[tokio::test]
+    async fn weather_is_read_from_mock_server() {
+        let dir = TestDir::new("weather");
+        let mock = start_mock();
+        let (mut receiver, _commands) = start_loop(&mock, NetatmoConfig::default(), &dir);
+
+        let data = receive(&mut receiver).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+        let location = &data.locations[0];
+        let weather = location.weather_data.as_ref().unwrap();
+        assert_eq!(weather.room_co2, 780);
+        assert_eq!(weather.outdoor_weather.as_ref().unwrap().temperature, -3.2);
+        assert_eq!(weather.rain.as_ref().unwrap().last_day, 3.8);
+        assert_eq!(weather.wind.as_ref().unwrap().gust_strength, 21);
+        assert_eq!(location.room_data["Переговорка"].room_co2, 1150);
+        assert_eq!(location.room_data["Детская"].room_humidity, 47);
+        assert!(!location.outdoor_history.series[&Measure::Temperature].is_empty());
+        assert!(!location.room_history["Дом"].series[&Measure::CO2].is_empty());
+
+        let requests = mock.requests();
+        for method in ["getstationsdata", "gethomecoachsdata", "getmeasure"] {
+            assert!(requests.iter().any(|r| r == method), "{} is not requested: {:?}", method, requests);
+        }
+    }
+
+    #[tokio::test]
+    async fn first_room_module_is_searched_in_own_station() {
+        let dir = TestDir::new("own-station");
+        let mock = start_mock();
+        let rooms = vec![ RoomConfig { title : String::from("Комната"), source : RoomSource::First, module_type : RoomModuleType::IndoorModule } ];
+        let locations = vec![
+            LocationConfig { title : String::from("Дом"), station : RoomSource::First, rooms : rooms.clone() },
+            LocationConfig { title : String::from("Дача"), station : RoomSource::StationName( String::from("Дача") ), rooms },
+        ];
+        let (mut receiver, _commands) = start_loop(&mock, NetatmoConfig { locations, ..NetatmoConfig::default() }, &dir);
+
+        let data = receive(&mut receiver).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+        // indoor module of the first station is unreachable
+        assert!(data.locations[0].room_data.is_empty());
+        assert_eq!(data.locations[1].room_data["Комната"].room_co2, 450);
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_auth_error() {
+        let dir = TestDir::new("expired-token");
+        let mock = start_mock();
+        mock.fail(ALL_METHODS, Some( Failure::TokenExpired ));
+        let (mut receiver, _commands) = start_loop(&mock, NetatmoConfig::default(), &dir);
+
+        let data = receive(&mut receiver).await;
+        assert_eq!(data.error.unwrap().kind, NetatmoErrorKind::Auth);
+        assert!(data.locations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn usage_reached_is_rate_limit_error() {
+        let dir = TestDir::new("usage-reached");
+        let mock = start_mock();
+        mock.fail("getstationsdata", Some( Failure::UsageReached ));
+        let (mut receiver, _commands) = start_loop(&mock, NetatmoConfig::default(), &dir);
+
+        let data = receive(&mut receiver).await;
+        assert_eq!(data.error.unwrap().kind, NetatmoErrorKind::RateLimit);
+    }
+
+    #[tokio::test]
+    async fn invalid_json_is_not_auth_error() {
+        let dir = TestDir::new("invalid-json");
+        let mock = start_mock();
+        mock.fail("getstationsdata", Some( Failure::InvalidJson ));
+        let (mut receiver, _commands) = start_loop(&mock, NetatmoConfig::default(), &dir);
+
+        let data = receive(&mut receiver).await;
+        assert_eq!(data.error.unwrap().kind, NetatmoErrorKind::Server);
+    }
+
+    #[tokio::test]
+    async fn unavailable_server_keeps_last_data() {
+        let dir = TestDir::new("unavailable");
+        let mock = start_mock();
+        let (mut receiver, commands) = start_loop(&mock, NetatmoConfig::default(), &dir);
+        let data = receive(&mut receiver).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+
+        mock.fail(ALL_METHODS, Some( Failure::Unavailable ));
+        let data = poll_again(&mut receiver, &commands).await;
+        let error = data.error.unwrap();
+        assert_eq!(error.kind, NetatmoErrorKind::Server);
+        assert_eq!(error.attempt, 1);
+        assert_eq!(data.locations[0].weather_data.as_ref().unwrap().room_co2, 780);
+
+        mock.fail(ALL_METHODS, None);
+        let data = poll_again(&mut receiver, &commands).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+    }
+
+    #[tokio::test]
+    async fn heating_error_keeps_weather_and_last_heating() {
+        let dir = TestDir::new("heating-error");
+        let mock = start_mock();
+        let netatmo_cfg = NetatmoConfig { heating : Some( HeatingConfig::default() ), ..NetatmoConfig::default() };
+        let (mut receiver, commands) = start_loop(&mock, netatmo_cfg, &dir);
+        let data = receive(&mut receiver).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+        assert_eq!(data.heating.as_ref().unwrap().rooms.len(), 2);
+
+        // thermostat scopes are missing
+        mock.fail("homestatus", Some( Failure::NoScope ));
+        let data = poll_again(&mut receiver, &commands).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+        assert!(data.locations[0].weather_data.is_some());
+        assert_eq!(data.heating.as_ref().unwrap().rooms.len(), 2);
+
+        // token is not expired by the heating error
+        let data = poll_again(&mut receiver, &commands).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+        assert!(!mock.requests().iter().any(|r| r == "token"), "{:?}", mock.requests());
+    }
+
+    #[tokio::test]
+    async fn setpoint_out_of_bounds_is_not_sent() {
+        let dir = TestDir::new("setpoint-bounds");
+        let mock = start_mock();
+        let netatmo_cfg = NetatmoConfig { heating : Some( HeatingConfig::default() ), ..NetatmoConfig::default() };
+        let (mut receiver, commands) = start_loop(&mock, netatmo_cfg, &dir);
+        let data = receive(&mut receiver).await;
+        let room_id = data.heating.as_ref().unwrap().rooms[0].id.clone();
+
+        commands.send( HomeCommand::SetRoomSetpoint( room_id.clone(), MAX_SETPOINT + 5.0 ) ).await.unwrap();
+        receive(&mut receiver).await;
+        assert!(!mock.requests().iter().any(|r| r == "setroomthermpoint"), "{:?}", mock.requests());
+
+        commands.send( HomeCommand::SetRoomSetpoint( room_id, MAX_SETPOINT ) ).await.unwrap();
+        receive(&mut receiver).await;
+        assert!(mock.requests().iter().any(|r| r == "setroomthermpoint"), "{:?}", mock.requests());
+    }
+
+    #[tokio::test]
+    async fn cold_start_authorizes_at_auth_url() {
+        let dir = TestDir::new("cold-start");
+        let mock = start_mock();
+        let (mut receiver, _commands) = start_loop_without_token(&mock, NetatmoConfig::default(), &dir);
+
+        let data = receive(&mut receiver).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+        assert_eq!(mock.requests()[..3], ["authorize", "token", "getstationsdata"]);
+        assert_eq!(load_token(&dir.0).unwrap().unwrap().access_token, "mock-access-token");
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_refreshed() {
+        let dir = TestDir::new("rejected-token");
+        let mock = start_mock();
+        mock.fail("getstationsdata", Some( Failure::InvalidToken ));
+        let (mut receiver, commands) = start_loop(&mock, NetatmoConfig::default(), &dir);
+        let data = receive(&mut receiver).await;
+        assert_eq!(data.error.unwrap().kind, NetatmoErrorKind::Auth);
+
+        mock.fail("getstationsdata", None);
+        let data = poll_again(&mut receiver, &commands).await;
+        assert!(data.error.is_none(), "{:?}", data.error);
+        let requests = mock.requests();
+        assert!(requests.iter().any(|r| r == "token"), "{:?}", requests);
+        assert!(!requests.iter().any(|r| r == "authorize"), "{:?}", requests);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+
+// stations and home coachs upload measurements every 10 minutes
+const UPLOAD_PERIOD : i64 = 10 * 60;
+// upload takes a while to show up in getstationsdata
+const UPLOAD_MARGIN : i64 = 30;
+const BUDGET_WINDOW : Duration = Duration::from_secs(3600);
+// learned budget is never lowered below it, otherwise there is no way to find out the quota is back
+const MIN_REQUESTS_PER_HOUR : usize = 10;
+
+/// delay until the nearest expected upload of any device, measured_at are unix times of their last readings.
+/// Late uploads are checked every min_interval.
+pub fn next_poll(measured_at : impl Iterator<Item = i64>, min_interval : Duration) -> Duration {
+  next_poll_at(measured_at, min_interval, Utc::now().timestamp())
+}
+
+fn next_poll_at(measured_at : impl Iterator<Item = i64>, min_interval : Duration, now : i64) -> Duration {
+  let nearest = measured_at
+    .map(|t| t + UPLOAD_PERIOD + UPLOAD_MARGIN - now)
+    .min();
+
+  match nearest {
+    // overdue upload is the nearest one as well
+    Some( wait ) => Duration::from_secs(wait.max(0) as u64).max(min_interval),
+    None => min_interval,
+  }
+}
+
+/// Requests made within the last hour, to stay within Netatmo quota.
+/// Quota is not reported by Netatmo, so the budget is lowered to the actual rate once quota errors appear,
+/// and the configured one is restored after an hour without them.
+pub struct RequestBudget {
+  per_hour : usize,
+  configured_per_hour : usize,
+  made : VecDeque<Instant>,
+  // time of the last quota error which lowered the budget
+  lowered_at : Option<Instant>,
+}
+
+impl RequestBudget {
+  pub fn new(per_hour : u32) -> Self {
+      let per_hour = (per_hour as usize).max(MIN_REQUESTS_PER_HOUR);
+      RequestBudget { per_hour, configured_per_hour : per_hour, made : VecDeque::new(), lowered_at : None }
+  }
+
+  pub fn record(&mut self, requests : u64) {
+      self.record_at(requests, Instant::now());
+  }
+
+  /// Netatmo said quota is exceeded
+  pub fn exhausted(&mut self) {
+      self.exhausted_at(Instant::now());
+  }
+
+  /// zero while there are requests left in the budget
+  pub fn delay(&mut self) -> Duration {
+      self.delay_at(Instant::now())
+  }
+
+  fn record_at(&mut self, requests : u64, now : Instant) {
+      self.made.resize(self.made.len() + requests as usize, now);
+      self.forget_old(now);
+  }
+
+  fn exhausted_at(&mut self, now : Instant) {
+      self.forget_old(now);
+      let per_hour = self.made.len().max(MIN_REQUESTS_PER_HOUR);
+      if per_hour < self.per_hour {
+          log::warn!("Lowering netatmo request budget from {} to {} per hour", self.per_hour, per_hour);
+          self.per_hour = per_hour;
+      }
+      if self.per_hour < self.configured_per_hour {
+          self.lowered_at = Some( now );
+      }
+  }
+
+  fn delay_at(&mut self, now : Instant) -> Duration {
+      self.forget_old(now);
+      if self.made.len() < self.per_hour {
+          return Duration::ZERO;
+      }
+      // wait until enough old requests leave the window
+      let oldest_to_leave = self.made[self.made.len() - self.per_hour];
+      BUDGET_WINDOW.saturating_sub( now.saturating_duration_since(oldest_to_leave) )
+  }
+
+  fn forget_old(&mut self, now : Instant) {
+      while self.made.front().is_some_and(|t| now.saturating_duration_since(*t) >= BUDGET_WINDOW) {
+          self.made.pop_front();
+      }
+      if self.lowered_at.is_some_and(|t| now.saturating_duration_since(t) >= BUDGET_WINDOW) {
+          log::info!("Restoring netatmo request budget to {} per hour", self.configured_per_hour);
+          self.per_hour = self.configured_per_hour;
+          self.lowered_at = None;
+      }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const NOW : i64 = 1_700_000_000;
+  const MIN_INTERVAL : Duration = Duration::from_secs(60);
+
+  #[test]
+  fn overdue_device_is_polled_after_min_interval() {
+      let overdue = NOW - 2 * UPLOAD_PERIOD;
+      let future = NOW - 60;
+      assert_eq!(next_poll_at([future, overdue].into_iter(), MIN_INTERVAL, NOW), MIN_INTERVAL);
+  }
+
+  #[test]
+  fn nearest_upload_is_waited_for() {
+      let delay = next_poll_at([NOW - 60, NOW - 300].into_iter(), MIN_INTERVAL, NOW);
+      assert_eq!(delay, Duration::from_secs((UPLOAD_PERIOD + UPLOAD_MARGIN - 300) as u64));
+  }
+
+  #[test]
+  fn no_data_is_polled_after_min_interval() {
+      assert_eq!(next_poll_at(std::iter::empty(), MIN_INTERVAL, NOW), MIN_INTERVAL);
+  }
+
+  #[test]
+  fn exhausted_budget_waits_for_requests_to_leave_window() {
+      let start = Instant::now();
+      let mut budget = RequestBudget::new(100);
+      budget.record_at(30, start);
+      assert_eq!(budget.delay_at(start), Duration::ZERO);
+
+      budget.exhausted_at(start);
+      assert_eq!(budget.per_hour, 30);
+      assert_eq!(budget.delay_at(start), BUDGET_WINDOW);
+      let later = start + Duration::from_secs(20 * 60);
+      assert_eq!(budget.delay_at(later), Duration::from_secs(40 * 60));
+  }
+
+  #[test]
+  fn budget_is_restored_after_hour_without_quota_errors() {
+      let start = Instant::now();
+      let mut budget = RequestBudget::new(100);
+      budget.record_at(30, start);
+      budget.exhausted_at(start);
+
+      // another quota error keeps the budget lowered for an hour since it
+      let error = start + Duration::from_secs(30 * 60);
+      budget.exhausted_at(error);
+      assert_eq!(budget.delay_at(start + BUDGET_WINDOW), Duration::ZERO);
+      assert_eq!(budget.per_hour, 30);
+
+      assert_eq!(budget.delay_at(error + BUDGET_WINDOW), Duration::ZERO);
+      assert_eq!(budget.per_hour, 100);
+  }
+}
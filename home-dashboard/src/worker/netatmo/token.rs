@@ -0,0 +1,142 @@
+use std::fs::{self, OpenOptions, Permissions};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use reqwest::header::LOCATION;
+use netatmo_connect::{ConnectConfig, Token, authorize, get_fresh_token};
+use crate::interface::CONFIGURATION_NAME;
+
+const TOKEN_FILE_NAME : &str = "netatmo-token.toml";
+// authorization at NetatmoConfig::auth_url is approved without user, the code is taken from the redirect
+// without following it, so nothing has to listen there
+const REDIRECT_URI : &str = "http://localhost/netatmo-callback";
+const SCOPES : &str = "read_station read_homecoach read_thermostat write_thermostat";
+
+/// Token as it's kept on disk. Instant is meaningless after restart, so expiration is unix time.
+#[derive(Serialize, Deserialize, Default)]
+struct StoredToken {
+  access_token : String,
+  refresh_token : String,
+  expires_at : u64,
+}
+
+/// token answer of OAuth token endpoint
+#[derive(Deserialize)]
+struct TokenAnswer {
+  access_token : String,
+  refresh_token : String,
+  // seconds
+  expires_in : u64,
+}
+
+/// token of previous run kept in dir, if there is any
+pub fn load_token(dir : &Path) -> Result<Option<Token>, String> {
+  let path = dir.join(TOKEN_FILE_NAME);
+  if !path.exists() {
+      return Ok( None );
+  }
+
+  let stored : StoredToken = confy::load_path(&path).map_err(|e| format!("Failed to load token from {}: {:?}", path.display(), e))?;
+  let expires_in = Duration::from_secs( stored.expires_at.saturating_sub( unix_now() ) );
+
+  Ok( Some( Token {
+    access_token : stored.access_token,
+    refresh_token : stored.refresh_token,
+    expires_at : Instant::now() + expires_in,
+  }))
+}
+
+pub fn store_token(dir : &Path, token : &Token) -> Result<(), String> {
+  let path = dir.join(TOKEN_FILE_NAME);
+  fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+  // file is created beforehand so it's never readable by others, confy keeps permissions of existing file
+  OpenOptions::new().write(true).create(true).truncate(false).mode(0o600).open(&path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+  fs::set_permissions(&path, Permissions::from_mode(0o600)).map_err(|e| format!("Failed to set permissions of {}: {}", path.display(), e))?;
+
+  let stored = StoredToken {
+    access_token : token.access_token.clone(),
+    refresh_token : token.refresh_token.clone(),
+    expires_at : unix_now() + token.expires_at.saturating_duration_since( Instant::now() ).as_secs(),
+  };
+  confy::store_path(&path, stored).map_err(|e| format!("Failed to store token to {}: {:?}", path.display(), e))
+}
+
+/// directory of configuration file, token and history are kept there
+pub fn data_dir() -> Result<PathBuf, String> {
+  let cfg_path = confy::get_configuration_file_path(CONFIGURATION_NAME, None).map_err(|e| format!("Failed to obtain configuration path: {:?}", e))?;
+  let dir = cfg_path.parent().ok_or( format!("Configuration path {} has no parent", cfg_path.display()) )?;
+  Ok( dir.to_path_buf() )
+}
+
+/// authorization by netatmo_connect at api.netatmo.com, or at auth_url if it's set
+pub async fn new_token(client : &reqwest::Client, cfg : &ConnectConfig, auth_url : Option<&str>, timeout : &Option<Duration>) -> Result<Token, String> {
+  match auth_url {
+    None => authorize(client, cfg, timeout).await,
+    Some( auth_url ) => authorize_at(auth_url, timeout).await,
+  }
+}
+
+pub async fn refreshed_token(client : &reqwest::Client, cfg : &ConnectConfig, auth_url : Option<&str>, token : &Token, timeout : &Option<Duration>) -> Result<Token, String> {
+  match auth_url {
+    None => get_fresh_token(client, cfg, token, timeout).await,
+    Some( auth_url ) => {
+      let params = [("grant_type", "refresh_token"), ("refresh_token", token.refresh_token.as_str())];
+      request_token(client, auth_url, &params, timeout).await
+    },
+  }
+}
+
+/// authorization code flow of a server which approves it without user, i.e. netatmo-mock.
+/// Client credentials are not checked there, so they are not sent.
+async fn authorize_at(auth_url : &str, timeout : &Option<Duration>) -> Result<Token, String> {
+  let client = reqwest::Client::builder()
+    .redirect( reqwest::redirect::Policy::none() )
+    .build()
+    .map_err(|e| format!("Failed to create authorization client: {}", e))?;
+
+  let mut request = client.get( format!("{}/authorize", auth_url.trim_end_matches('/')) )
+    .query(&[("redirect_uri", REDIRECT_URI), ("scope", SCOPES)]);
+  if let Some( timeout ) = timeout {
+      request = request.timeout(*timeout);
+  }
+  let res = request.send().await.map_err(|e| format!("Authorization request failed: {}", e))?;
+  let status = res.status();
+  if !status.is_redirection() {
+      return Err( format!("Authorization failed with {}: {}", status, res.text().await.unwrap_or_default()) );
+  }
+
+  let location = res.headers().get(LOCATION).and_then(|l| l.to_str().ok()).ok_or( String::from("Authorization redirect has no location") )?;
+  let redirect = reqwest::Url::parse(location).map_err(|e| format!("Wrong authorization redirect {}: {}", location, e))?;
+  let code = redirect.query_pairs().find(|(k, _)| k == "code").map(|(_, v)| v.into_owned())
+    .ok_or( format!("Authorization redirect {} has no code", location) )?;
+
+  let params = [("grant_type", "authorization_code"), ("code", code.as_str()), ("redirect_uri", REDIRECT_URI)];
+  request_token(&client, auth_url, &params, timeout).await
+}
+
+/// errors have HTTP status and OAuth error in the text like the ones of netatmo_connect, so they are classified the same way
+async fn request_token(client : &reqwest::Client, auth_url : &str, params : &[(&str, &str)], timeout : &Option<Duration>) -> Result<Token, String> {
+  let mut request = client.post( format!("{}/token", auth_url.trim_end_matches('/')) ).form(params);
+  if let Some( timeout ) = timeout {
+      request = request.timeout(*timeout);
+  }
+  let res = request.send().await.map_err(|e| format!("Token request failed: {}", e))?;
+  let status = res.status();
+  let text = res.text().await.map_err(|e| format!("Failed to read token response: {}", e))?;
+  if !status.is_success() {
+      return Err( format!("Token request failed with {}: {}", status, text) );
+  }
+
+  let answer : TokenAnswer = serde_json::from_str(&text).map_err(|e| format!("Failed to parse token response: {}", e))?;
+  Ok( Token {
+    access_token : answer.access_token,
+    refresh_token : answer.refresh_token,
+    expires_at : Instant::now() + Duration::from_secs(answer.expires_in),
+  })
+}
+
+fn unix_now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}